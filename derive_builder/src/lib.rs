@@ -87,6 +87,34 @@ impl StructContext {
         )
     }
 
+    pub fn leaf_codec_render(&self) -> TokenStream2 {
+        let name = self.name.clone();
+        let fields = self
+            .fields
+            .iter()
+            .map(|f| f.name.clone())
+            .collect::<Vec<_>>();
+        quote!(
+            impl LeafCodec for #name {
+                fn encode(&self, buf: &mut Vec<u64>) {
+                    #(self.#fields.encode(buf);)*
+                }
+
+                fn decode(buf: &mut &[u64]) -> Self {
+                    Self {
+                        #(#fields: LeafCodec::decode(buf),)*
+                    }
+                }
+
+                fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+                    Some(Self {
+                        #(#fields: LeafCodec::try_decode(buf)?,)*
+                    })
+                }
+            }
+        )
+    }
+
     fn witness_reader(&self) -> Vec<TokenStream2> {
         let mut ret = vec![];
         for i in 0..self.fields.len() {
@@ -150,6 +178,45 @@ impl EnumContext {
         )
     }
 
+    pub fn leaf_codec_render(&self) -> TokenStream2 {
+        let name = self.name.clone();
+        let indexes = (0..self.variants.len() as u64).collect::<Vec<_>>();
+        let variants = self
+            .variants
+            .iter()
+            .map(|v| v.name.clone())
+            .collect::<Vec<_>>();
+        quote!(
+            impl LeafCodec for #name {
+                fn encode(&self, buf: &mut Vec<u64>) {
+                    match self {
+                        #(Self::#variants(obj) => {
+                            buf.push(#indexes);
+                            obj.encode(buf);
+                        })*
+                    }
+                }
+
+                fn decode(buf: &mut &[u64]) -> Self {
+                    match <u64 as LeafCodec>::decode(buf) {
+                        #(#indexes => Self::#variants(LeafCodec::decode(buf)),)*
+                        _ => {
+                            unsafe { require(false) };
+                            unreachable!()
+                        }
+                    }
+                }
+
+                fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+                    match <u64 as LeafCodec>::try_decode(buf)? {
+                        #(#indexes => Some(Self::#variants(LeafCodec::try_decode(buf)?)),)*
+                        _ => None
+                    }
+                }
+            }
+        )
+    }
+
     fn witness_reader(&self) -> Vec<TokenStream2> {
         let mut ret = vec![];
         for i in 0..self.variants.len() {
@@ -189,3 +256,28 @@ pub fn derive_witness_obj(input: TokenStream) -> TokenStream {
         Context::E(e) => e.witness_obj_render().into(),
     }
 }
+
+#[proc_macro_derive(LeafCodec)]
+pub fn derive_leaf_codec(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let c = Context::from(input);
+    match c {
+        Context::S(s) => s.leaf_codec_render().into(),
+        Context::E(e) => e.leaf_codec_render().into(),
+    }
+}
+
+/// Keys are derived by hashing the `LeafCodec` encoding of the value
+#[proc_macro_derive(KeyCodec)]
+pub fn derive_key_codec(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    quote!(
+        impl KeyCodec for #name {
+            fn to_key(&self) -> [u64; 4] {
+                KeyCodec::to_key(&LeafCodec::to_limbs(self))
+            }
+        }
+    )
+    .into()
+}
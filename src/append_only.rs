@@ -0,0 +1,101 @@
+use crate::merkle::{Merkle, MerkleError};
+use crate::require;
+
+/// Reserved leaf which keeps the number of appended entries
+const LENGTH_INDEX: u32 = u32::MAX;
+
+/// Append only log on top of a merkle. Entries are stored with `Merkle::set` at
+/// index 0, 1, 2 ... and the length of the log is kept in the last leaf.
+pub struct AppendOnlyMerkle {
+    pub merkle: Merkle,
+}
+
+impl Default for AppendOnlyMerkle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppendOnlyMerkle {
+    pub fn new() -> Self {
+        AppendOnlyMerkle {
+            merkle: Merkle::new(),
+        }
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        AppendOnlyMerkle {
+            merkle: Merkle::load(root),
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        let mut leaf = [0; 4];
+        self.merkle.get_simple(LENGTH_INDEX, &mut leaf);
+        leaf[0] as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append data at the end of the log and return its index
+    pub fn append(&mut self, data: &[u64]) -> u32 {
+        let index = self.len();
+        unsafe {
+            require(!data.is_empty());
+            require(index < LENGTH_INDEX);
+        }
+        // the hint enforces that the slot has never been written
+        self.merkle.set(index, data, true, Some(&[0; 4]));
+        self.merkle.set_simple(
+            LENGTH_INDEX,
+            &[index as u64 + 1, 0, 0, 0],
+            Some(&[index as u64, 0, 0, 0]),
+        );
+        index
+    }
+
+    /// Append data at the given index, which must be the current length of the log
+    pub fn append_at(&mut self, index: u32, data: &[u64]) {
+        unsafe { require(index == self.len()) };
+        self.append(data);
+    }
+
+    pub fn get(&self, index: u32) -> Vec<u64> {
+        unsafe { require(index < self.len()) };
+        let (_, data) = self.merkle.get(index, true);
+        data
+    }
+
+    /// Same as get but reports an index past the end of the log or a missing or
+    /// corrupt preimage instead of failing the proof
+    pub fn try_get(&self, index: u32) -> Result<Vec<u64>, MerkleError> {
+        if index >= self.len() {
+            return Err(MerkleError::IndexOutOfRange);
+        }
+        let (_, data) = self.merkle.try_get(index, true)?;
+        Ok(data)
+    }
+
+    /// Check that the log with `new_root` was obtained from the log with `old_root`
+    /// by appends only. This costs one leaf read in each tree per entry of the old log.
+    pub fn is_consistent(old_root: [u64; 4], new_root: [u64; 4]) -> bool {
+        let old = Self::load(old_root);
+        let new = Self::load(new_root);
+        let len = old.len();
+        if len > new.len() {
+            return false;
+        }
+        let mut old_leaf = [0; 4];
+        let mut new_leaf = [0; 4];
+        for index in 0..len {
+            old.merkle.get_simple(index, &mut old_leaf);
+            new.merkle.get_simple(index, &mut new_leaf);
+            if old_leaf != new_leaf {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,140 @@
+use crate::codec::KeyCodec;
+use crate::kvpair::{KeyValueMap, SMT};
+use crate::merkle::Merkle;
+use crate::poseidon::PoseidonHasher;
+use crate::require;
+use std::marker::PhantomData;
+
+// leaf holding the flag and the mask of the flag in its limb
+fn bit_position(bit: u64) -> (usize, u64) {
+    ((bit / 64) as usize % 4, 1 << (bit % 64))
+}
+
+/// Set of flags on top of a merkle (e.g. claimed airdrop indexes), 256 flags are packed in
+/// the raw data of each leaf so flag i is bit `i % 256` of leaf `i / 256`. Leaves are
+/// accessed with `get_simple`/`set_simple` and the flags are never cleared.
+pub struct MerkleBitmap {
+    pub merkle: Merkle,
+}
+
+impl Default for MerkleBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleBitmap {
+    pub fn new() -> Self {
+        Self::load(Merkle::new().root)
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        MerkleBitmap {
+            merkle: Merkle::load(root),
+        }
+    }
+
+    // index of the leaf of the flag, the flags fit in 2^40 bits
+    fn leaf_index(index: u64) -> u32 {
+        unsafe { require(index >> 40 == 0) };
+        (index >> 8) as u32
+    }
+
+    pub fn test(&self, index: u64) -> bool {
+        let mut leaf = [0; 4];
+        self.merkle.get_simple(Self::leaf_index(index), &mut leaf);
+        let (limb, mask) = bit_position(index);
+        leaf[limb] & mask != 0
+    }
+
+    /// Set the flag, which may already be set
+    pub fn set(&mut self, index: u64) {
+        self.update(index);
+    }
+
+    /// Set the flag, fails if it was already set (e.g. a second claim)
+    pub fn test_and_set(&mut self, index: u64) {
+        let was_set = self.update(index);
+        unsafe { require(!was_set) };
+    }
+
+    /// Set the flag and return false if it was already set, so that a second claim can be
+    /// rejected without failing the proof
+    #[must_use]
+    pub fn try_set(&mut self, index: u64) -> bool {
+        !self.update(index)
+    }
+
+    // set the flag and return whether it was set before
+    fn update(&mut self, index: u64) -> bool {
+        let leaf_index = Self::leaf_index(index);
+        let mut leaf = [0; 4];
+        self.merkle.get_simple(leaf_index, &mut leaf);
+        let (limb, mask) = bit_position(index);
+        let was_set = leaf[limb] & mask != 0;
+        if !was_set {
+            let mut new_leaf = leaf;
+            new_leaf[limb] |= mask;
+            // the get above is the get of the get/set pair
+            unsafe {
+                self.merkle.set_simple_unsafe(leaf_index, &new_leaf);
+            }
+        }
+        was_set
+    }
+}
+
+/// Sparse set of keys such as nullifiers, kept in a `KeyValueMap` where each member has
+/// its own leaf holding `[1]`. Keys are encoded with `KeyCodec` and the encoding is hashed,
+/// so members are spread over the map even for sequential keys. Flags are not packed since
+/// hashed keys do not share leaves, use `MerkleBitmap` for dense integer flags.
+pub struct SparseBitmap<K: KeyCodec + ?Sized, S: SMT> {
+    pub map: KeyValueMap<S>,
+    _marker: PhantomData<fn(&K)>,
+}
+
+impl<K: KeyCodec + ?Sized, S: SMT> SparseBitmap<K, S> {
+    pub fn new(root_merkle: S) -> Self {
+        SparseBitmap {
+            map: KeyValueMap::new(root_merkle),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Key of the leaf of the member in the map
+    pub fn member_key(key: &K) -> [u64; 4] {
+        PoseidonHasher::hash(&key.to_key(), true)
+    }
+
+    pub fn test(&self, key: &K) -> bool {
+        let member = self.map.get(&Self::member_key(key));
+        unsafe { require(member.is_empty() || member == [1]) };
+        !member.is_empty()
+    }
+
+    /// Set the flag of the key, which may already be set
+    pub fn set(&mut self, key: &K) {
+        self.update(key);
+    }
+
+    /// Set the flag of the key, fails if it was already set (e.g. a nullifier spent twice)
+    pub fn test_and_set(&mut self, key: &K) {
+        let was_set = self.update(key);
+        unsafe { require(!was_set) };
+    }
+
+    /// Set the flag of the key and return false if it was already set, so that a double
+    /// spend can be rejected without failing the proof
+    #[must_use]
+    pub fn try_set(&mut self, key: &K) -> bool {
+        !self.update(key)
+    }
+
+    fn update(&mut self, key: &K) -> bool {
+        let was_set = self.test(key);
+        if !was_set {
+            self.map.set(&Self::member_key(key), &[1]);
+        }
+        was_set
+    }
+}
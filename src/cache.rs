@@ -21,6 +21,14 @@ pub fn store_data(hash: &[u64; 4], data: &[u64]) {
 
 // It is better for the following to be phantom if data has large size
 pub fn fetch_data(hash: &[u64; 4], data: &mut [u64]) -> u64 {
+    let len = try_fetch_data(hash, data);
+    unsafe { crate::require(len <= data.len() as u64) };
+    len
+}
+
+/// Same as fetch_data but does not abort when the data does not fit in the buffer.
+/// The returned length is then larger than the buffer and the buffer content is unspecified.
+pub fn try_fetch_data(hash: &[u64; 4], data: &mut [u64]) -> u64 {
     unsafe {
         cache_set_mode(0);
         cache_set_hash(hash[0]);
@@ -28,13 +36,14 @@ pub fn fetch_data(hash: &[u64; 4], data: &mut [u64]) -> u64 {
         cache_set_hash(hash[2]);
         cache_set_hash(hash[3]);
         let len = cache_fetch_data();
-        if len > 0 {
-            crate::require(len <= data.len() as u64);
-            for i in 0..len as usize {
-                data[i] = cache_fetch_data();
+        for i in 0..len as usize {
+            // keep consuming the data so that the cache stays in sync
+            let v = cache_fetch_data();
+            if i < data.len() {
+                data[i] = v;
             }
         }
-        return len;
+        len
     }
 }
 
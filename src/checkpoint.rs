@@ -0,0 +1,75 @@
+use crate::kvpair::{KeyValueMap, SMT};
+use crate::merkle::Merkle;
+use crate::require;
+
+/// State whose content is fully determined by a merkle root
+pub trait MerkleRoot {
+    fn root(&self) -> [u64; 4];
+    fn restore_root(&mut self, root: [u64; 4]);
+}
+
+impl MerkleRoot for Merkle {
+    fn root(&self) -> [u64; 4] {
+        self.root
+    }
+
+    fn restore_root(&mut self, root: [u64; 4]) {
+        self.root = root;
+    }
+}
+
+impl<S: SMT + MerkleRoot> MerkleRoot for KeyValueMap<S> {
+    fn root(&self) -> [u64; 4] {
+        self.merkle.root()
+    }
+
+    fn restore_root(&mut self, root: [u64; 4]) {
+        self.merkle.restore_root(root);
+    }
+}
+
+/// Stack of saved roots so that a batch of updates can be tried and discarded
+/// without failing the whole proof through `require`.
+///
+/// A rollback only restores the root. Leaves written after the checkpoint (including
+/// the leaves of sub merkles created by the kvpair) stay in the host merkle db and
+/// the preimages stored by `Merkle::set` stay in the data cache. Nothing reachable
+/// from the restored root refers to them any more, so these cache entries become
+/// stale. Since the cache is keyed by hash they can never be read back with wrong
+/// content, a later `set` of the same data simply refers to them again.
+#[derive(Default)]
+pub struct Checkpoints {
+    roots: Vec<[u64; 4]>,
+}
+
+impl Checkpoints {
+    pub fn new() -> Self {
+        Checkpoints { roots: vec![] }
+    }
+
+    /// Number of checkpoints that are neither committed nor rolled back
+    pub fn depth(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Save the current root of the state, checkpoints can be nested
+    pub fn checkpoint(&mut self, state: &impl MerkleRoot) {
+        self.roots.push(state.root());
+    }
+
+    /// Restore the root saved by the innermost checkpoint and drop that checkpoint.
+    /// Returns the discarded root whose cache entries are now stale.
+    pub fn rollback(&mut self, state: &mut impl MerkleRoot) -> [u64; 4] {
+        unsafe { require(!self.roots.is_empty()) };
+        let discarded = state.root();
+        state.restore_root(self.roots.pop().unwrap());
+        discarded
+    }
+
+    /// Keep all changes made since the innermost checkpoint and drop that checkpoint.
+    /// The changes can still be discarded by rolling back an outer checkpoint.
+    pub fn commit(&mut self) {
+        unsafe { require(!self.roots.is_empty()) };
+        self.roots.pop();
+    }
+}
@@ -0,0 +1,268 @@
+use crate::jubjub::BabyJubjubPoint;
+use crate::poseidon::PoseidonHasher;
+use crate::require;
+use primitive_types::U256;
+
+/// Encoding of a record into the u64 limbs which are stored behind a merkle leaf.
+///
+/// Structs and enums whose fields implement `LeafCodec` can derive it with
+/// `derive_builder::LeafCodec`. Fields are encoded in declaration order and enums
+/// are prefixed with the index of their variant.
+pub trait LeafCodec: Sized {
+    /// Append the limbs of self to buf
+    fn encode(&self, buf: &mut Vec<u64>);
+
+    /// Decode from the head of buf and advance buf past the consumed limbs, or return None
+    /// if the limbs are not a valid encoding (e.g. a malformed preimage)
+    fn try_decode(buf: &mut &[u64]) -> Option<Self>;
+
+    /// Same as try_decode but fails the proof on an invalid encoding
+    fn decode(buf: &mut &[u64]) -> Self {
+        let v = Self::try_decode(buf);
+        unsafe { require(v.is_some()) };
+        v.unwrap()
+    }
+
+    fn to_limbs(&self) -> Vec<u64> {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Decode a value which must use all the limbs of data
+    fn from_limbs(data: &[u64]) -> Self {
+        let mut buf = data;
+        let v = Self::decode(&mut buf);
+        unsafe { require(buf.is_empty()) };
+        v
+    }
+
+    /// Same as from_limbs but returns None instead of failing the proof
+    fn try_from_limbs(data: &[u64]) -> Option<Self> {
+        let mut buf = data;
+        let v = Self::try_decode(&mut buf)?;
+        buf.is_empty().then_some(v)
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u64], len: usize) -> Option<&'a [u64]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+impl LeafCodec for u64 {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.push(*self);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        Some(take(buf, 1)?[0])
+    }
+}
+
+macro_rules! impl_small_int_codec {
+    ($($t:ty),*) => {
+        $(
+            impl LeafCodec for $t {
+                fn encode(&self, buf: &mut Vec<u64>) {
+                    buf.push(*self as u64);
+                }
+
+                fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+                    let v = take(buf, 1)?[0];
+                    (v <= <$t>::MAX as u64).then_some(v as $t)
+                }
+            }
+        )*
+    };
+}
+
+impl_small_int_codec!(u8, u16, u32);
+
+impl LeafCodec for i64 {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.push(*self as u64);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        Some(take(buf, 1)?[0] as i64)
+    }
+}
+
+impl LeafCodec for bool {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.push(*self as u64);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        let v = take(buf, 1)?[0];
+        (v <= 1).then_some(v == 1)
+    }
+}
+
+impl LeafCodec for [u64; 4] {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        take(buf, 4)?.try_into().ok()
+    }
+}
+
+impl LeafCodec for U256 {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.extend_from_slice(&self.0);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        Some(U256(LeafCodec::try_decode(buf)?))
+    }
+}
+
+impl LeafCodec for BabyJubjubPoint {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        self.x.encode(buf);
+        self.y.encode(buf);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        Some(BabyJubjubPoint {
+            x: LeafCodec::try_decode(buf)?,
+            y: LeafCodec::try_decode(buf)?,
+        })
+    }
+}
+
+/// Vectors are prefixed with their length
+impl<T: LeafCodec> LeafCodec for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        buf.push(self.len() as u64);
+        for t in self {
+            t.encode(buf);
+        }
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        let len = take(buf, 1)?[0];
+        let mut v = vec![];
+        for _ in 0..len {
+            v.push(T::try_decode(buf)?);
+        }
+        Some(v)
+    }
+}
+
+impl<A: LeafCodec, B: LeafCodec> LeafCodec for (A, B) {
+    fn encode(&self, buf: &mut Vec<u64>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+
+    fn try_decode(buf: &mut &[u64]) -> Option<Self> {
+        let a = A::try_decode(buf)?;
+        let b = B::try_decode(buf)?;
+        Some((a, b))
+    }
+}
+
+/// Values of a `TypedKeyValueMap`, which are encoded like merkle leaves
+pub trait ValueCodec: LeafCodec {}
+
+impl<T: LeafCodec> ValueCodec for T {}
+
+/// Encoding of a map key into the `[u64; 4]` key of a `KeyValueMap`.
+///
+/// Integers and 4 limb values are used as is, while byte strings and limb vectors are
+/// hashed with their length. Structs and enums implementing `LeafCodec` can derive it
+/// with `derive_builder::KeyCodec`, which hashes their leaf encoding.
+pub trait KeyCodec {
+    fn to_key(&self) -> [u64; 4];
+}
+
+impl KeyCodec for u64 {
+    fn to_key(&self) -> [u64; 4] {
+        [*self, 0, 0, 0]
+    }
+}
+
+impl KeyCodec for u32 {
+    fn to_key(&self) -> [u64; 4] {
+        [*self as u64, 0, 0, 0]
+    }
+}
+
+impl KeyCodec for u128 {
+    fn to_key(&self) -> [u64; 4] {
+        [*self as u64, (*self >> 64) as u64, 0, 0]
+    }
+}
+
+impl KeyCodec for [u64; 4] {
+    fn to_key(&self) -> [u64; 4] {
+        *self
+    }
+}
+
+impl KeyCodec for U256 {
+    fn to_key(&self) -> [u64; 4] {
+        self.0
+    }
+}
+
+impl KeyCodec for [u64] {
+    fn to_key(&self) -> [u64; 4] {
+        let mut buf = Vec::with_capacity(self.len() + 1);
+        buf.push(self.len() as u64);
+        buf.extend_from_slice(self);
+        PoseidonHasher::hash(&buf, true)
+    }
+}
+
+impl KeyCodec for Vec<u64> {
+    fn to_key(&self) -> [u64; 4] {
+        self.as_slice().to_key()
+    }
+}
+
+/// Pack bytes into little endian u64 limbs, the last limb is padded with zeros
+pub fn bytes_to_limbs(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks(8)
+        .map(|chunk| {
+            let mut limb = [0u8; 8];
+            limb[0..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(limb)
+        })
+        .collect()
+}
+
+impl KeyCodec for [u8] {
+    fn to_key(&self) -> [u64; 4] {
+        let mut buf = vec![self.len() as u64];
+        buf.extend(bytes_to_limbs(self));
+        PoseidonHasher::hash(&buf, true)
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn to_key(&self) -> [u64; 4] {
+        self.as_slice().to_key()
+    }
+}
+
+impl KeyCodec for str {
+    fn to_key(&self) -> [u64; 4] {
+        self.as_bytes().to_key()
+    }
+}
+
+impl KeyCodec for String {
+    fn to_key(&self) -> [u64; 4] {
+        self.as_bytes().to_key()
+    }
+}
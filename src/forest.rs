@@ -0,0 +1,84 @@
+use crate::merkle::Merkle;
+use crate::wasm_output;
+
+struct ForestTree {
+    id: u32,
+    // leaf of the top merkle the last time it was read or written
+    stored: [u64; 4],
+    merkle: Merkle,
+}
+
+/// Several merkle trees (e.g. balances, orders, config and events) committed under
+/// a single state root. The root of the tree with identifier `id` is kept as the raw
+/// leaf `id` of the top merkle, where an empty leaf stands for an empty tree.
+///
+/// Sub trees are loaded on first access and the top merkle is only updated when the
+/// combined root is requested, so a sub tree can be updated many times at the cost
+/// of a single top level write.
+pub struct MerkleForest {
+    pub top: Merkle,
+    trees: Vec<ForestTree>,
+}
+
+impl Default for MerkleForest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleForest {
+    pub fn new() -> Self {
+        Self::load(Merkle::new().root)
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        MerkleForest {
+            top: Merkle::load(root),
+            trees: vec![],
+        }
+    }
+
+    /// The tree with the given identifier
+    pub fn tree(&mut self, id: u32) -> &mut Merkle {
+        let pos = match self.trees.iter().position(|t| t.id == id) {
+            Some(pos) => pos,
+            None => {
+                let mut stored = [0; 4];
+                self.top.get_simple(id, &mut stored);
+                let merkle = if stored == [0; 4] {
+                    Merkle::new()
+                } else {
+                    Merkle::load(stored)
+                };
+                self.trees.push(ForestTree { id, stored, merkle });
+                self.trees.len() - 1
+            }
+        };
+        &mut self.trees[pos].merkle
+    }
+
+    /// Write back the roots of the trees that changed and return the combined root
+    pub fn root(&mut self) -> [u64; 4] {
+        let default_root = Merkle::new().root;
+        for tree in self.trees.iter_mut() {
+            let leaf = if tree.merkle.root == default_root {
+                [0; 4]
+            } else {
+                tree.merkle.root
+            };
+            if leaf != tree.stored {
+                self.top.set_simple(tree.id, &leaf, Some(&tree.stored));
+                tree.stored = leaf;
+            }
+        }
+        self.top.root
+    }
+
+    /// Output the combined root through `wasm_output`
+    pub fn output_root(&mut self) {
+        let root = self.root();
+        for v in root {
+            unsafe { wasm_output(v) };
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::merkle::Merkle;
+use crate::require;
+
+/// Ring buffer of the last `capacity` roots of some other state, so that proofs made
+/// against any recent root can still be accepted (as in privacy pool designs).
+///
+/// The roots are kept in the leaves `base .. base + capacity` of a merkle and the leaf
+/// `base + capacity` keeps the cursor `[next slot, number of roots, 0, 0]`.
+pub struct RootHistory {
+    pub merkle: Merkle,
+    base: u32,
+    capacity: u32,
+}
+
+impl RootHistory {
+    pub fn new(merkle: Merkle, base: u32, capacity: u32) -> Self {
+        unsafe {
+            require(capacity > 0);
+            require((base as u64) + (capacity as u64) < u32::MAX as u64);
+        }
+        RootHistory {
+            merkle,
+            base,
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn cursor(&self) -> [u64; 4] {
+        let mut cursor = [0; 4];
+        self.merkle
+            .get_simple(self.base + self.capacity, &mut cursor);
+        cursor
+    }
+
+    /// Number of roots kept, at most capacity
+    pub fn len(&self) -> u32 {
+        self.cursor()[1] as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Record a new root, which replaces the oldest one once the buffer is full
+    pub fn push_root(&mut self, root: &[u64; 4]) {
+        let cursor = self.cursor();
+        let next = cursor[0] as u32;
+        let len = cursor[1] as u32;
+        self.merkle.set_simple(self.base + next, root, None);
+        self.merkle.set_simple(
+            self.base + self.capacity,
+            &[
+                ((next + 1) % self.capacity) as u64,
+                (len + 1).min(self.capacity) as u64,
+                0,
+                0,
+            ],
+            Some(&cursor),
+        );
+    }
+
+    fn slot_matches(&self, root: &[u64; 4], slot: u32) -> bool {
+        let mut leaf = [0; 4];
+        self.merkle.get_simple(self.base + slot, &mut leaf);
+        leaf == *root
+    }
+
+    /// Check the root against the recorded slot given by an untrusted witness
+    pub fn is_known_root_at(&self, root: &[u64; 4], slot: u32) -> bool {
+        slot < self.len() && self.slot_matches(root, slot)
+    }
+
+    /// Slot of the root if it is one of the recorded roots
+    pub fn find_root(&self, root: &[u64; 4]) -> Option<u32> {
+        (0..self.len()).find(|slot| self.slot_matches(root, *slot))
+    }
+
+    /// Check whether the root is one of the recorded roots, which costs one leaf read
+    /// per recorded root. Use `is_known_root_at` when the slot is known.
+    pub fn is_known_root(&self, root: &[u64; 4]) -> bool {
+        self.find_root(root).is_some()
+    }
+
+    /// Fail the proof unless the root is one of the recorded roots, to be called by
+    /// proof verification helpers before checking a proof against a recent root
+    pub fn require_known_root(&self, root: &[u64; 4]) {
+        unsafe { require(self.is_known_root(root)) };
+    }
+}
@@ -1,15 +1,157 @@
+use crate::codec::{bytes_to_limbs, KeyCodec, ValueCodec};
+use crate::poseidon::PoseidonHasher;
+use crate::require;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+
+/// Witness that a key is absent from a `KeyValueMap`: the tree nodes on the path of the
+/// key, the content of the terminal leaf, which is either empty or the leaf of a different
+/// key, and for each level the sibling hashes on the path of the key in the merkle of that
+/// level. It is checked against a root alone with `NonMembershipWitness::verify`.
+///
+/// The host merkle gives no access to sibling hashes, so witnesses are built off-chain on
+/// a backend implementing `SMTWitness`, such as `native::NativeMerkle`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NonMembershipWitness {
+    pub nodes: Vec<Vec<u64>>,
+    pub terminal: Vec<u64>,
+    /// siblings from the leaf up to the root, one path per level
+    pub siblings: Vec<[[u64; 4]; 32]>,
+}
+
 pub trait SMT {
     fn smt_get(&self, key: &[u64; 4]) -> Vec<u64>;
     fn smt_set(&mut self, key: &[u64; 4], data: &[u64]);
+    /// Remove the key and return whether it was present
+    fn smt_remove(&mut self, key: &[u64; 4]) -> bool;
+    /// Same as setting the entries one by one, a key given twice keeps its last value
+    fn smt_set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+        for (key, data) in entries {
+            self.smt_set(key, data);
+        }
+    }
+    /// Same as getting the keys one by one
+    fn smt_get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        keys.iter().map(|key| self.smt_get(key)).collect()
+    }
+}
+
+/// SMT whose backend opens merkle paths, which non membership witnesses need
+pub trait SMTWitness: SMT {
+    /// Witness that the key is absent, or None if the key is present
+    fn smt_non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness>;
 }
 
+impl<S: SMTWitness + ?Sized> SMTWitness for &mut S {
+    fn smt_non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness> {
+        (**self).smt_non_membership(key)
+    }
+}
+
+/// Sparse merkle tree over u64 keys, the leaf of a key is `[key, value0, value1, flags]`
+/// so that each key holds up to two limbs
 pub trait SMTU64 {
-    fn smt_get(&self, key: u64) -> u64;
-    fn smt_set(&mut self, key: u64, data: u64);
+    /// The two value limbs of the key, or None if the key is absent
+    fn smt_get(&self, key: u64) -> Option<[u64; 2]>;
+    fn smt_set(&mut self, key: u64, data: &[u64; 2]);
+    /// Remove the key and return whether it was present
+    fn smt_remove(&mut self, key: u64) -> bool;
+}
+
+/// Lets a map borrow an SMT which it does not own
+impl<S: SMT + ?Sized> SMT for &mut S {
+    fn smt_get(&self, key: &[u64; 4]) -> Vec<u64> {
+        (**self).smt_get(key)
+    }
+    fn smt_set(&mut self, key: &[u64; 4], data: &[u64]) {
+        (**self).smt_set(key, data)
+    }
+    fn smt_remove(&mut self, key: &[u64; 4]) -> bool {
+        (**self).smt_remove(key)
+    }
+    fn smt_set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+        (**self).smt_set_many(entries)
+    }
+    fn smt_get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        (**self).smt_get_many(keys)
+    }
+}
+
+/// Lets several maps share one SMT at the same time, e.g. a few `Namespace` wrappers over
+/// one `RefCell<Merkle>`. Each access borrows the cell for the duration of the call only.
+impl<S: SMT> SMT for &RefCell<S> {
+    fn smt_get(&self, key: &[u64; 4]) -> Vec<u64> {
+        self.borrow().smt_get(key)
+    }
+    fn smt_set(&mut self, key: &[u64; 4], data: &[u64]) {
+        self.borrow_mut().smt_set(key, data)
+    }
+    fn smt_remove(&mut self, key: &[u64; 4]) -> bool {
+        self.borrow_mut().smt_remove(key)
+    }
+    fn smt_set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+        self.borrow_mut().smt_set_many(entries)
+    }
+    fn smt_get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        self.borrow().smt_get_many(keys)
+    }
+}
+
+impl<S: SMTWitness> SMTWitness for &RefCell<S> {
+    fn smt_non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness> {
+        self.borrow().smt_non_membership(key)
+    }
+}
+
+/// Key of a compact sparse merkle tree, which is kept in the leaf itself next to the
+/// value. Each 32 bits of the key select the index in the merkle of one level, so a
+/// key of one limb uses up to two levels and a key of two limbs up to four.
+pub trait SmtKey {
+    /// Number of leaf limbs taken by the key
+    const LIMBS: usize;
+    /// Number of leaf limbs left for the value, the last limb keeps the flags
+    const VALUE_LIMBS: usize = 3 - Self::LIMBS;
+    /// Limbs of the key, only the first `LIMBS` of them are used
+    fn to_limbs(&self) -> [u64; 2];
+}
+
+impl SmtKey for u64 {
+    const LIMBS: usize = 1;
+    fn to_limbs(&self) -> [u64; 2] {
+        [*self, 0]
+    }
+}
+
+impl SmtKey for u128 {
+    const LIMBS: usize = 2;
+    fn to_limbs(&self) -> [u64; 2] {
+        [*self as u64, (*self >> 64) as u64]
+    }
+}
+
+impl SmtKey for [u64; 2] {
+    const LIMBS: usize = 2;
+    fn to_limbs(&self) -> [u64; 2] {
+        *self
+    }
+}
+
+/// Sparse merkle tree over `SmtKey` keys with the leaf `[key limbs.., value limbs.., flags]`,
+/// the u64 instance has the same roots as `SMTU64`
+pub trait SMTCompact<K: SmtKey> {
+    /// The `K::VALUE_LIMBS` value limbs of the key, or None if the key is absent
+    fn smt_get(&self, key: &K) -> Option<Vec<u64>>;
+    fn smt_set(&mut self, key: &K, data: &[u64]);
+    /// Remove the key and return whether it was present
+    fn smt_remove(&mut self, key: &K) -> bool;
 }
 
 /// sparse merkle tree implemented by adding indicators at leafs of each group (32 depth)
-/// to indicate whether the leaf is a data leaf or a root of a deeper merkle tree
+/// to indicate whether the leaf is a data leaf or a root of a deeper merkle tree.
+/// A deeper merkle tree always holds at least two keys, so the root only depends on the
+/// content of the map and not on the order of the updates. The key count of a deeper tree
+/// is a hint kept in the data cache, deeper trees written before that hint existed are
+/// read and updated as they are but not collapsed.
 pub struct KeyValueMap<S: SMT> {
     pub merkle: S,
 }
@@ -26,6 +168,27 @@ impl<S: SMT> KeyValueMap<S> {
     pub fn get(&self, key: &[u64; 4]) -> Vec<u64> {
         self.merkle.smt_get(key)
     }
+    /// Remove the key, a deeper merkle tree left with a single key is collapsed into its parent
+    pub fn remove(&mut self, key: &[u64; 4]) -> bool {
+        self.merkle.smt_remove(key)
+    }
+    /// Set a batch of keys with the same result as setting them one by one (a key given
+    /// twice keeps its last value). Keys are grouped by the leaves on their paths, so a
+    /// leaf shared by several keys and the sub merkle behind it are written once.
+    pub fn set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+        self.merkle.smt_set_many(entries);
+    }
+    /// Get a batch of keys, reading each leaf shared by several keys once
+    pub fn get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        self.merkle.smt_get_many(keys)
+    }
+}
+
+impl<S: SMTWitness> KeyValueMap<S> {
+    /// Witness that the key is absent, or None if the key is present
+    pub fn non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness> {
+        self.merkle.smt_non_membership(key)
+    }
 }
 
 pub struct KeyValueMapU64<S: SMTU64> {
@@ -39,9 +202,251 @@ impl<S: SMTU64> KeyValueMapU64<S> {
         }
     }
     pub fn set(&mut self, key: u64, data: u64) {
+        self.merkle.smt_set(key, &[data, 0]);
+    }
+    /// The value of the key, or None if the key is absent, so that a missing key is
+    /// told apart from a key holding zero
+    pub fn get(&self, key: u64) -> Option<u64> {
+        self.merkle.smt_get(key).map(|data| data[0])
+    }
+    /// Store a two limb record such as `(balance, nonce)` under the key
+    pub fn set_pair(&mut self, key: u64, data: &[u64; 2]) {
+        self.merkle.smt_set(key, data);
+    }
+    pub fn get_pair(&self, key: u64) -> Option<[u64; 2]> {
+        self.merkle.smt_get(key)
+    }
+    pub fn contains(&self, key: u64) -> bool {
+        self.merkle.smt_get(key).is_some()
+    }
+    /// Remove the key and return whether it was present. A deeper merkle tree is only
+    /// cleared once it holds no key, so unlike `KeyValueMap` the root may depend on
+    /// the order of the updates after a removal.
+    pub fn remove(&mut self, key: u64) -> bool {
+        self.merkle.smt_remove(key)
+    }
+}
+
+/// KeyValueMapU64 generalised to wider keys such as u128 or `[u64; 2]` (e.g. account and
+/// token pairs), each key holds `K::VALUE_LIMBS` limbs
+pub struct CompactKeyValueMap<K: SmtKey, S: SMTCompact<K>> {
+    pub merkle: S,
+    _marker: PhantomData<K>,
+}
+
+impl<K: SmtKey, S: SMTCompact<K>> CompactKeyValueMap<K, S> {
+    pub fn new(root_merkle: S) -> Self {
+        CompactKeyValueMap {
+            merkle: root_merkle,
+            _marker: PhantomData,
+        }
+    }
+    /// Store the value limbs of the key, data must have `K::VALUE_LIMBS` limbs
+    pub fn set(&mut self, key: &K, data: &[u64]) {
         self.merkle.smt_set(key, data);
     }
-    pub fn get(&self, key: u64) -> u64 {
+    pub fn get(&self, key: &K) -> Option<Vec<u64>> {
         self.merkle.smt_get(key)
     }
+    pub fn contains(&self, key: &K) -> bool {
+        self.merkle.smt_get(key).is_some()
+    }
+    /// Remove the key and return whether it was present, see `KeyValueMapU64::remove`
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.merkle.smt_remove(key)
+    }
+}
+
+/// KeyValueMap with typed keys and values, keys are encoded with `KeyCodec` and
+/// values with `ValueCodec`. A stored value never has an empty encoding, so `get`
+/// tells a missing key apart from a key holding an empty vector.
+pub struct TypedKeyValueMap<K: KeyCodec + ?Sized, V: ValueCodec, S: SMT> {
+    pub map: KeyValueMap<S>,
+    _marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K: KeyCodec + ?Sized, V: ValueCodec, S: SMT> TypedKeyValueMap<K, V, S> {
+    pub fn new(root_merkle: S) -> Self {
+        TypedKeyValueMap {
+            map: KeyValueMap::new(root_merkle),
+            _marker: PhantomData,
+        }
+    }
+    pub fn set(&mut self, key: &K, value: &V) {
+        let data = value.to_limbs();
+        unsafe { require(!data.is_empty()) };
+        self.map.set(&key.to_key(), &data);
+    }
+    pub fn get(&self, key: &K) -> Option<V> {
+        let data = self.map.get(&key.to_key());
+        if data.is_empty() {
+            None
+        } else {
+            Some(V::from_limbs(&data))
+        }
+    }
+    pub fn contains(&self, key: &K) -> bool {
+        !self.map.get(&key.to_key()).is_empty()
+    }
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.map.remove(&key.to_key())
+    }
+}
+
+/// KeyValueMap keyed by byte strings of any length (user names, urls, address and token
+/// id pairs ...). The path in the SMT is chosen by the Poseidon hash of the key, and the
+/// full key is stored in the leaf in front of the value as
+/// [key length in bytes, key packed into limbs .., value ..].
+/// Lookups compare the full key, so a hash collision fails the proof instead of
+/// silently returning or overwriting the record of another key.
+pub struct BytesKeyValueMap<S: SMT> {
+    pub map: KeyValueMap<S>,
+}
+
+impl<S: SMT> BytesKeyValueMap<S> {
+    pub fn new(root_merkle: S) -> Self {
+        BytesKeyValueMap {
+            map: KeyValueMap::new(root_merkle),
+        }
+    }
+
+    // returns the stored record of the hashed key after checking it holds the same key
+    fn get_record(&self, key: &[u8], limbs: &[u64]) -> Vec<u64> {
+        let record = self.map.get(&key.to_key());
+        if !record.is_empty() {
+            unsafe {
+                require(record.len() > limbs.len());
+                require(record[0] == key.len() as u64);
+                require(record[1..limbs.len() + 1] == *limbs);
+            }
+        }
+        record
+    }
+
+    pub fn set(&mut self, key: &[u8], data: &[u64]) {
+        let limbs = bytes_to_limbs(key);
+        self.get_record(key, &limbs);
+        let record = [&[key.len() as u64], limbs.as_slice(), data].concat();
+        self.map.set(&key.to_key(), &record);
+    }
+
+    /// Returns None if the key is absent
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u64>> {
+        let limbs = bytes_to_limbs(key);
+        let record = self.get_record(key, &limbs);
+        if record.is_empty() {
+            None
+        } else {
+            Some(record[limbs.len() + 1..].to_vec())
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        let limbs = bytes_to_limbs(key);
+        if self.get_record(key, &limbs).is_empty() {
+            false
+        } else {
+            self.map.remove(&key.to_key())
+        }
+    }
+}
+
+/// Domain tags of the hashes which derive namespaced keys and the key of the namespace list
+const NAMESPACE_KEY_DOMAIN: u64 = u64::from_le_bytes(*b"ns/key\0\0");
+const NAMESPACE_LIST_DOMAIN: u64 = u64::from_le_bytes(*b"ns/list\0");
+
+/// View of an SMT where each key is replaced by the Poseidon hash of
+/// [domain tag, namespace id, key], so that several logical maps (balances, allowances,
+/// metadata ...) can share one root without their keys colliding. The wrapped SMT is
+/// usually a `&RefCell<Merkle>` shared by the namespaces, and a namespace is itself an SMT
+/// which can back a `KeyValueMap`, `TypedKeyValueMap` or `BytesKeyValueMap`.
+///
+/// Opening a namespace does not touch the SMT. The ids of the namespaces registered on a
+/// root with `register_namespace` are kept under a reserved key and can be read back with
+/// `namespace_list`.
+pub struct Namespace<S: SMT> {
+    pub merkle: S,
+    id: u64,
+}
+
+impl<S: SMT> Namespace<S> {
+    pub fn new(merkle: S, id: u64) -> Self {
+        Namespace { merkle, id }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Key in the wrapped SMT of the key of this namespace, which is also the key
+    /// against which non membership witnesses are verified
+    pub fn key(&self, key: &[u64; 4]) -> [u64; 4] {
+        PoseidonHasher::hash(
+            &[
+                NAMESPACE_KEY_DOMAIN,
+                self.id,
+                key[0],
+                key[1],
+                key[2],
+                key[3],
+            ],
+            true,
+        )
+    }
+}
+
+fn namespace_list_key() -> [u64; 4] {
+    PoseidonHasher::hash(&[NAMESPACE_LIST_DOMAIN], true)
+}
+
+/// Add the namespace to the namespace list of the SMT, returns false if it was already there
+pub fn register_namespace<S: SMT + ?Sized>(merkle: &mut S, id: u64) -> bool {
+    let list_key = namespace_list_key();
+    let mut ids = merkle.smt_get(&list_key);
+    if ids.contains(&id) {
+        return false;
+    }
+    ids.push(id);
+    merkle.smt_set(&list_key, &ids);
+    true
+}
+
+/// Ids of the namespaces registered on the SMT in the order they were registered
+pub fn namespace_list<S: SMT + ?Sized>(merkle: &S) -> Vec<u64> {
+    merkle.smt_get(&namespace_list_key())
+}
+
+impl<S: SMT> SMT for Namespace<S> {
+    fn smt_get(&self, key: &[u64; 4]) -> Vec<u64> {
+        self.merkle.smt_get(&self.key(key))
+    }
+    fn smt_set(&mut self, key: &[u64; 4], data: &[u64]) {
+        let key = self.key(key);
+        self.merkle.smt_set(&key, data)
+    }
+    fn smt_remove(&mut self, key: &[u64; 4]) -> bool {
+        let key = self.key(key);
+        self.merkle.smt_remove(&key)
+    }
+    fn smt_set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+        let entries: Vec<([u64; 4], &[u64])> = entries
+            .iter()
+            .map(|(key, data)| (self.key(key), *data))
+            .collect();
+        self.merkle.smt_set_many(&entries)
+    }
+    fn smt_get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        let keys: Vec<[u64; 4]> = keys.iter().map(|key| self.key(key)).collect();
+        self.merkle.smt_get_many(&keys)
+    }
+}
+
+impl<S: SMTWitness> SMTWitness for Namespace<S> {
+    fn smt_non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness> {
+        self.merkle.smt_non_membership(&self.key(key))
+    }
 }
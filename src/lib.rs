@@ -38,10 +38,22 @@ extern "C" {
 
 #[cfg(feature = "witness")]
 pub mod allocator;
+pub mod append_only;
+pub mod bitmap;
 pub mod cache;
+pub mod checkpoint;
+pub mod codec;
+pub mod forest;
+pub mod history;
 pub mod jubjub;
 pub mod kvpair;
 pub mod merkle;
+pub mod merkle_queue;
+pub mod merkle_vec;
+pub mod mmr;
+#[cfg(feature = "native")]
+pub mod native;
+pub mod ordered_map;
 pub mod poseidon;
 #[cfg(feature = "witness")]
 pub mod witness;
@@ -7,9 +7,28 @@ extern "C" {
 }
 
 use crate::cache;
-use crate::kvpair::{SMT, SMTU64};
+use crate::checkpoint::MerkleRoot;
+use crate::codec::LeafCodec;
+use crate::kvpair::{KeyValueMap, NonMembershipWitness, SmtKey, SMT};
 use crate::poseidon::PoseidonHasher;
 use crate::require;
+use std::marker::PhantomData;
+
+/// Failures of merkle accesses that a guest can recover from, e.g. by rejecting
+/// the transaction that provided the input instead of failing the whole proof
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkleError {
+    /// the leaf is not empty but the cache has no preimage for it
+    CacheMiss,
+    /// the preimage (or the hint) does not match the leaf
+    HashMismatch,
+    /// the preimage does not fit in the given buffer
+    BufferTooSmall,
+    /// the index is outside of the range used by the structure
+    IndexOutOfRange,
+    /// the preimage is not a valid encoding of the record stored in the leaf
+    InvalidEncoding,
+}
 
 pub struct Merkle {
     pub root: [u64; 4],
@@ -81,63 +100,119 @@ impl Merkle {
 
     /// Set the raw leaf data of a merkle subtree
     pub fn set_simple(&mut self, index: u32, data: &[u64; 4], hint: Option<&[u64; 4]>) {
+        let r = self.try_set_simple(index, data, hint);
+        unsafe { require(r.is_ok()) };
+    }
+
+    /// Set the raw leaf data of a merkle subtree, the leaf is left unchanged and
+    /// HashMismatch is returned if the current leaf does not match the hint
+    pub fn try_set_simple(
+        &mut self,
+        index: u32,
+        data: &[u64; 4],
+        hint: Option<&[u64; 4]>,
+    ) -> Result<(), MerkleError> {
         // place a dummy get for merkle proof convension
+        let mut current = [0; 4];
         unsafe {
             merkle_address(index as u64);
             merkle_setroot(self.root[0]);
             merkle_setroot(self.root[1]);
             merkle_setroot(self.root[2]);
             merkle_setroot(self.root[3]);
-        }
-        if let Some(hint_data) = hint {
-            unsafe {
-                require(hint_data[0] == merkle_get());
-                require(hint_data[1] == merkle_get());
-                require(hint_data[2] == merkle_get());
-                require(hint_data[3] == merkle_get());
-            }
-        } else {
-            unsafe {
-                merkle_get();
-                merkle_get();
-                merkle_get();
-                merkle_get();
-            }
-        }
-        unsafe {
+
+            current[0] = merkle_get();
+            current[1] = merkle_get();
+            current[2] = merkle_get();
+            current[3] = merkle_get();
+
             //enforce root does not change
             merkle_getroot();
             merkle_getroot();
             merkle_getroot();
             merkle_getroot();
-
+        }
+        if let Some(hint_data) = hint {
+            if *hint_data != current {
+                return Err(MerkleError::HashMismatch);
+            }
+        }
+        unsafe {
             // perform the set
             self.set_simple_unsafe(index, data);
         }
+        Ok(())
+    }
+
+    /// Get the raw leaf data of `len` consecutive leaves starting at `start`.
+    /// The host merkle interface takes one address per call, so this loops over `get_simple`.
+    pub fn get_range(&self, start: u32, len: u32) -> Vec<[u64; 4]> {
+        unsafe { require((start as u64) + (len as u64) <= (u32::MAX as u64) + 1) };
+        let mut leaves = vec![[0; 4]; len as usize];
+        for (index, leaf) in (start..=u32::MAX).zip(leaves.iter_mut()) {
+            self.get_simple(index, leaf);
+        }
+        leaves
+    }
+
+    /// Set the raw leaf data of consecutive leaves starting at `start`.
+    /// The host merkle interface takes one address per call, so this loops over `set_simple`.
+    pub fn set_range(&mut self, start: u32, data: &[[u64; 4]]) {
+        unsafe { require((start as u64) + (data.len() as u64) <= (u32::MAX as u64) + 1) };
+        for (index, leaf) in (start..=u32::MAX).zip(data.iter()) {
+            self.set_simple(index, leaf, None);
+        }
     }
 
     pub fn get(&self, index: u32, pad: bool) -> ([u64; 4], Vec<u64>) {
+        let r = self.try_get(index, pad);
+        unsafe { require(r.is_ok()) };
+        r.unwrap()
+    }
+
+    /// Same as get but reports a missing or corrupt preimage instead of failing the proof
+    pub fn try_get(&self, index: u32, pad: bool) -> Result<([u64; 4], Vec<u64>), MerkleError> {
         let mut hash = [0; 4];
         self.get_simple(index, &mut hash);
+        if hash == [0; 4] {
+            // empty (or removed) leaf, no need to ask the cache
+            return Ok((hash, vec![]));
+        }
         let data = cache::get_data(&hash);
-        if data.len() > 0 {
+        Self::check_preimage(&hash, &data, pad)?;
+        Ok((hash, data))
+    }
+
+    /// Same as try_get but fetches the preimage into buf and returns its length
+    pub fn try_get_into(
+        &self,
+        index: u32,
+        pad: bool,
+        buf: &mut [u64],
+    ) -> Result<([u64; 4], usize), MerkleError> {
+        let mut hash = [0; 4];
+        self.get_simple(index, &mut hash);
+        if hash == [0; 4] {
+            return Ok((hash, 0));
+        }
+        let len = cache::try_fetch_data(&hash, buf) as usize;
+        if len > buf.len() {
+            return Err(MerkleError::BufferTooSmall);
+        }
+        Self::check_preimage(&hash, &buf[0..len], pad)?;
+        Ok((hash, len))
+    }
+
+    fn check_preimage(hash: &[u64; 4], data: &[u64], pad: bool) -> Result<(), MerkleError> {
+        if !data.is_empty() {
             // FIXME: avoid copy here
-            let hash_check = PoseidonHasher::hash(&data, pad);
-            unsafe {
-                require(hash[0] == hash_check[0]);
-                require(hash[1] == hash_check[1]);
-                require(hash[2] == hash_check[2]);
-                require(hash[3] == hash_check[3]);
-            }
-        } else {
-            unsafe {
-                require(hash[0] == 0);
-                require(hash[1] == 0);
-                require(hash[2] == 0);
-                require(hash[3] == 0);
+            if PoseidonHasher::hash(data, pad) != *hash {
+                return Err(MerkleError::HashMismatch);
             }
+        } else if *hash != [0; 4] {
+            return Err(MerkleError::CacheMiss);
         }
-        (hash, data)
+        Ok(())
     }
 
     /// safe version of set which enforces a get before set
@@ -147,6 +222,27 @@ impl Merkle {
         self.set_simple(index, &hash, hint);
     }
 
+    /// Same as set but reports a hint mismatch instead of failing the proof
+    pub fn try_set(
+        &mut self,
+        index: u32,
+        data: &[u64],
+        pad: bool,
+        hint: Option<&[u64; 4]>,
+    ) -> Result<(), MerkleError> {
+        let hash = PoseidonHasher::hash(data, pad);
+        cache::store_data(&hash, data);
+        self.try_set_simple(index, &hash, hint)
+    }
+
+    /// Clear the leaf back to the empty (zero) leaf so that `get` treats the index as empty.
+    /// Like `set_simple` this enforces the get/set pair convention and checks the hint.
+    /// Once the leaf is zeroed its preimage is no longer referenced by it, the cache entry
+    /// itself is left alone since the cache has no way to drop data.
+    pub fn remove(&mut self, index: u32, hint: Option<&[u64; 4]>) {
+        self.set_simple(index, &[0; 4], hint);
+    }
+
     /// unsafe version of set which does not enforce the get/set pair convention
     pub unsafe fn set_unsafe(&mut self, index: u32, data: &[u64], pad: bool) {
         let hash = PoseidonHasher::hash(data, pad);
@@ -155,6 +251,185 @@ impl Merkle {
     }
 }
 
+/// Leaf access used by the sparse merkle trees of `kvpair`, so that the same SMT code runs
+/// on the host merkle (`Merkle`) and on the in-memory `native::NativeMerkle`
+pub trait MerkleBackend: MerkleRoot + Sized {
+    fn new() -> Self;
+    fn load(root: [u64; 4]) -> Self;
+    fn get_simple(&self, index: u32, data: &mut [u64; 4]);
+    /// # Safety
+    /// Does not enforce the get/set pair convention, see `Merkle::set_simple_unsafe`
+    unsafe fn set_simple_unsafe(&mut self, index: u32, data: &[u64; 4]);
+    fn set_simple(&mut self, index: u32, data: &[u64; 4], hint: Option<&[u64; 4]>);
+    fn get(&self, index: u32, pad: bool) -> ([u64; 4], Vec<u64>);
+    fn set(&mut self, index: u32, data: &[u64], pad: bool, hint: Option<&[u64; 4]>);
+    /// # Safety
+    /// Does not enforce the get/set pair convention, see `Merkle::set_unsafe`
+    unsafe fn set_unsafe(&mut self, index: u32, data: &[u64], pad: bool);
+    /// Keep data next to the merkles under a root. The data is not covered by any root,
+    /// so it can only be used as a hint which is checked against the merkle.
+    fn store_root_hint(root: &[u64; 4], data: &[u64]);
+    /// Data kept with `store_root_hint`, empty if there is none
+    fn fetch_root_hint(root: &[u64; 4]) -> Vec<u64>;
+}
+
+impl MerkleBackend for Merkle {
+    fn new() -> Self {
+        Merkle::new()
+    }
+    fn load(root: [u64; 4]) -> Self {
+        Merkle::load(root)
+    }
+    fn get_simple(&self, index: u32, data: &mut [u64; 4]) {
+        Merkle::get_simple(self, index, data)
+    }
+    unsafe fn set_simple_unsafe(&mut self, index: u32, data: &[u64; 4]) {
+        Merkle::set_simple_unsafe(self, index, data)
+    }
+    fn set_simple(&mut self, index: u32, data: &[u64; 4], hint: Option<&[u64; 4]>) {
+        Merkle::set_simple(self, index, data, hint)
+    }
+    fn get(&self, index: u32, pad: bool) -> ([u64; 4], Vec<u64>) {
+        Merkle::get(self, index, pad)
+    }
+    fn set(&mut self, index: u32, data: &[u64], pad: bool, hint: Option<&[u64; 4]>) {
+        Merkle::set(self, index, data, pad, hint)
+    }
+    unsafe fn set_unsafe(&mut self, index: u32, data: &[u64], pad: bool) {
+        Merkle::set_unsafe(self, index, data, pad)
+    }
+    // the data cache is keyed by any hash, so the hints share it with the leaf preimages
+    fn store_root_hint(root: &[u64; 4], data: &[u64]) {
+        cache::store_data(root, data)
+    }
+    fn fetch_root_hint(root: &[u64; 4]) -> Vec<u64> {
+        cache::get_data(root)
+    }
+}
+
+/// Backends which open the merkle path of a leaf. The host merkle only gives access to the
+/// leaves, so this is implemented by off-chain backends such as `native::NativeMerkle`.
+pub trait MerklePath: MerkleBackend {
+    /// Hashes of the siblings on the path of the leaf, from the leaf up to the root
+    fn siblings(&self, index: u32) -> [[u64; 4]; 32];
+}
+
+/// Layout of fixed size records spread over consecutive raw leaves, four limbs per leaf.
+/// Record `i` occupies the leaves `i * leaves() .. (i + 1) * leaves()` and the unused
+/// limbs of its last leaf are zero. Small records stored this way are read and written
+/// with `get_simple`/`set_simple`, which avoids the cache round trip and the re-hash of
+/// `Merkle::get`. A record of all zeros can not be told apart from an absent record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordLayout {
+    limbs: usize,
+}
+
+impl RecordLayout {
+    pub const fn new(limbs: usize) -> Self {
+        RecordLayout { limbs }
+    }
+
+    pub fn limbs(&self) -> usize {
+        self.limbs
+    }
+
+    /// Number of leaves used by each record
+    pub fn leaves(&self) -> u32 {
+        ((self.limbs + 3) / 4) as u32
+    }
+
+    /// Index of the first leaf of the record
+    pub fn first_leaf(&self, record: u32) -> u32 {
+        let index = (record as u64) * (self.leaves() as u64);
+        unsafe { require(index + (self.leaves() as u64) <= (u32::MAX as u64) + 1) };
+        index as u32
+    }
+}
+
+impl Merkle {
+    /// Read a record laid out over consecutive raw leaves
+    pub fn get_record(&self, layout: &RecordLayout, record: u32) -> Vec<u64> {
+        let leaves = self.get_range(layout.first_leaf(record), layout.leaves());
+        let mut data = leaves.concat();
+        data.truncate(layout.limbs());
+        data
+    }
+
+    /// Write a record laid out over consecutive raw leaves
+    pub fn set_record(&mut self, layout: &RecordLayout, record: u32, data: &[u64]) {
+        unsafe { require(data.len() == layout.limbs()) };
+        let leaves = data
+            .chunks(4)
+            .map(|chunk| {
+                let mut leaf = [0; 4];
+                leaf[0..chunk.len()].copy_from_slice(chunk);
+                leaf
+            })
+            .collect::<Vec<_>>();
+        self.set_range(layout.first_leaf(record), &leaves);
+    }
+}
+
+/// Merkle whose leaves hold records of type T encoded with `LeafCodec`.
+/// The leaves are stored with `Merkle::set` so the hash verification is unchanged.
+pub struct TypedMerkle<T: LeafCodec> {
+    pub merkle: Merkle,
+    _marker: PhantomData<T>,
+}
+
+impl<T: LeafCodec> Default for TypedMerkle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: LeafCodec> TypedMerkle<T> {
+    pub fn new() -> Self {
+        Self::load(Merkle::new().root)
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        TypedMerkle {
+            merkle: Merkle::load(root),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns None if the leaf is empty
+    pub fn get(&self, index: u32) -> Option<T> {
+        let (_, data) = self.merkle.get(index, true);
+        if data.is_empty() {
+            None
+        } else {
+            Some(T::from_limbs(&data))
+        }
+    }
+
+    /// Same as get but reports a missing, corrupt or malformed preimage instead of failing
+    /// the proof
+    pub fn try_get(&self, index: u32) -> Result<Option<T>, MerkleError> {
+        let (_, data) = self.merkle.try_get(index, true)?;
+        if data.is_empty() {
+            Ok(None)
+        } else {
+            T::try_from_limbs(&data)
+                .map(Some)
+                .ok_or(MerkleError::InvalidEncoding)
+        }
+    }
+
+    pub fn set(&mut self, index: u32, value: &T) {
+        let data = value.to_limbs();
+        // an empty encoding can not be told apart from an empty leaf
+        unsafe { require(!data.is_empty()) };
+        self.merkle.set(index, &data, true, None);
+    }
+
+    pub fn remove(&mut self, index: u32) {
+        self.merkle.remove(index, None);
+    }
+}
+
 const LEAF_NODE: u64 = 0;
 const TREE_NODE: u64 = 1;
 
@@ -179,7 +454,73 @@ fn set_smt_data(t: u64, key: &[u64], data: &[u64]) -> Vec<u64> {
     [buf, node_buf].concat()
 }
 
-impl Merkle {
+// A tree node [TREE_NODE, root[0..4]] only holds the root of its sub merkle. The number of
+// keys below it and their (wrapping) sum are kept as a hint under the sub root instead (see
+// `MerkleBackend::store_root_hint`), so that a sub merkle left with a single key after a
+// removal can be collapsed into its parent without changing the node format. The hint is
+// not trusted as is: the collapse rebuilds the sub root from the hinted leaf alone. Sub
+// merkles written before the hint existed have none and are not collapsed.
+fn tree_node_data(root: &[u64; 4]) -> Vec<u64> {
+    set_smt_data(TREE_NODE, root, &[])
+}
+
+fn key_sum_add(sum: &[u64; 4], key: &[u64]) -> [u64; 4] {
+    [
+        sum[0].wrapping_add(key[0]),
+        sum[1].wrapping_add(key[1]),
+        sum[2].wrapping_add(key[2]),
+        sum[3].wrapping_add(key[3]),
+    ]
+}
+
+fn key_sum_sub(sum: &[u64; 4], key: &[u64]) -> [u64; 4] {
+    [
+        sum[0].wrapping_sub(key[0]),
+        sum[1].wrapping_sub(key[1]),
+        sum[2].wrapping_sub(key[2]),
+        sum[3].wrapping_sub(key[3]),
+    ]
+}
+
+// The SMT of `KeyValueMap` written against `MerkleBackend`, shared by all the backends
+pub(crate) trait SmtLocal: MerkleBackend {
+    // the number and the sum of the keys of the sub merkle with the given root, if hinted
+    fn smt_sub_info(root: &[u64; 4]) -> Option<(u64, [u64; 4])> {
+        let hint = Self::fetch_root_hint(root);
+        (hint.len() == 5).then(|| (hint[0], hint[1..5].try_into().unwrap()))
+    }
+
+    fn smt_store_sub_info(root: &[u64; 4], info: Option<(u64, [u64; 4])>) {
+        if let Some((count, key_sum)) = info {
+            Self::store_root_hint(
+                root,
+                &[count, key_sum[0], key_sum[1], key_sum[2], key_sum[3]],
+            );
+        }
+    }
+
+    // The hash of the leaf of this sub merkle (at path_index) if it holds a single key, so
+    // that the leaf can be moved up into the parent. The key is taken from the hinted info
+    // and only trusted once the root is rebuilt from its leaf alone.
+    fn smt_single_leaf(
+        &self,
+        info: Option<(u64, [u64; 4])>,
+        path_index: usize,
+    ) -> Option<[u64; 4]> {
+        let (count, key) = info?;
+        if count != 1 {
+            return None;
+        }
+        let index = (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+        let (leaf_hash, leaf) = self.get(index, true);
+        if leaf.is_empty() || (leaf[0] & 0x1) != LEAF_NODE || !data_matches_key(&leaf, &key) {
+            return None;
+        }
+        let mut single = Self::new();
+        single.set_simple(index, &leaf_hash, None);
+        (single.root() == self.root()).then_some(leaf_hash)
+    }
+
     fn smt_get_local(&self, key: &[u64; 4], path_index: usize) -> Vec<u64> {
         unsafe { require(path_index < 8) };
         let local_index = (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
@@ -201,71 +542,432 @@ impl Merkle {
             } else {
                 // crate::dbg!("smt_get_local is node: continue in sub merkle\n");
                 unsafe { require((data[0] & 0x1) == TREE_NODE) };
-                let sub_merkle = Merkle::load(data[1..5].try_into().unwrap());
+                let sub_merkle = Self::load(data[1..5].try_into().unwrap());
                 sub_merkle.smt_get_local(key, path_index + 1)
             }
         }
     }
 
-    fn smt_set_local(&mut self, key: &[u64], path_index: usize, data: &[u64]) {
+    // read the path of the key from this merkle (at path_index) down to the terminal leaf
+    fn smt_walk(&self, key: &[u64; 4], path_index: usize) -> Vec<SmtStep<Self>> {
+        let mut steps = vec![];
+        let mut merkle = Self::load(self.root());
+        for path_index in path_index..8 {
+            let index = (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+            let (hash, content) = merkle.get(index, true);
+            let sub_merkle = if !content.is_empty() && (content[0] & 0x1) == TREE_NODE {
+                Some(Self::load(content[1..5].try_into().unwrap()))
+            } else {
+                None
+            };
+            steps.push(SmtStep {
+                merkle,
+                index,
+                hash,
+                content,
+            });
+            match sub_merkle {
+                Some(sub_merkle) => merkle = sub_merkle,
+                None => return steps,
+            }
+        }
+        // the key is exhausted while the path still goes deeper
+        unsafe { require(false) };
+        unreachable!()
+    }
+
+    // write data for the key along a path read by smt_walk from path_index. Returns the new
+    // root of the merkle the walk started from and whether the key was not in the tree
+    // before. Every leaf is written with the leaf read by the walk as hint.
+    fn smt_write_path(
+        mut steps: Vec<SmtStep<Self>>,
+        key: &[u64; 4],
+        data: &[u64],
+        path_index: usize,
+    ) -> ([u64; 4], bool) {
+        let step = steps.pop().unwrap();
+        let level = path_index + steps.len();
+        let inserted = step.content.is_empty() || !data_matches_key(&step.content, key);
+        let node_buf = if step.content.is_empty() || !inserted {
+            set_smt_data(LEAF_NODE, key, data)
+        } else {
+            // conflict with the leaf of another key, start a new sub merkle
+            let mut sub_merkle = Self::new();
+            sub_merkle.smt_set_local(&step.content[1..5], level + 1, &step.content[5..]);
+            sub_merkle.smt_set_local(key, level + 1, data);
+            let key_sum = key_sum_add(&step.content[1..5].try_into().unwrap(), key);
+            Self::smt_store_sub_info(&sub_merkle.root(), Some((2, key_sum)));
+            tree_node_data(&sub_merkle.root())
+        };
+        let mut root = step.write(&node_buf);
+        while let Some(step) = steps.pop() {
+            let info = Self::smt_sub_info(&step.content[1..5].try_into().unwrap());
+            let info = if inserted {
+                info.map(|(count, key_sum)| (count + 1, key_sum_add(&key_sum, key)))
+            } else {
+                info
+            };
+            Self::smt_store_sub_info(&root, info);
+            root = step.write(&tree_node_data(&root));
+        }
+        (root, inserted)
+    }
+
+    // returns true if the key was not in the tree before
+    fn smt_set_local(&mut self, key: &[u64], path_index: usize, data: &[u64]) -> bool {
+        unsafe { require(path_index < 8) };
+        let key: [u64; 4] = key.try_into().unwrap();
+        let steps = self.smt_walk(&key, path_index);
+        let (root, inserted) = Self::smt_write_path(steps, &key, data, path_index);
+        *self = Self::load(root);
+        inserted
+    }
+
+    // returns true if the key was in the tree
+    fn smt_remove_local(&mut self, key: &[u64; 4], path_index: usize) -> bool {
         unsafe { require(path_index < 8) };
         let local_index = (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
-        let (_, content) = self.get(local_index, true);
-        if content.len() == 0 {
-            // let root = self.root;
-            // crate::dbg!("smt add new leaf {:?} {:?}\n", root, data);
-            let node_buf = set_smt_data(LEAF_NODE, key, data);
-            unsafe {
-                self.set_unsafe(local_index, node_buf.as_slice(), true);
+        let (hash, content) = self.get(local_index, true);
+        if content.is_empty() {
+            false
+        } else if (content[0] & 0x1) == LEAF_NODE {
+            if data_matches_key(content.as_slice(), key) {
+                // the get above is the get of the get/set pair
+                unsafe {
+                    self.set_simple_unsafe(local_index, &[0; 4]);
+                }
+                true
+            } else {
+                false
             }
         } else {
-            //crate::dbg!("smt set local hit:\n");
-            if (content[0] & 0x1) == LEAF_NODE {
-                //crate::dbg!("current node for set is leaf:\n");
-                if data_matches_key(content.as_slice(), key) {
-                    //crate::dbg!("key match update data:\n");
-                    // if hit the current node
-                    let node_buf = set_smt_data(LEAF_NODE, key, data);
-                    unsafe {
-                        self.set_unsafe(local_index, node_buf.as_slice(), true);
+            unsafe { require((content[0] & 0x1) == TREE_NODE) };
+            let sub_root: [u64; 4] = content[1..5].try_into().unwrap();
+            let mut sub_merkle = Self::load(sub_root);
+            if !sub_merkle.smt_remove_local(key, path_index + 1) {
+                return false;
+            }
+            let info = Self::smt_sub_info(&sub_root)
+                .map(|(count, key_sum)| (count.saturating_sub(1), key_sum_sub(&key_sum, key)));
+            if sub_merkle.root() == Self::new().root() {
+                // the last key of a sub merkle which was not collapsed for lack of a hint
+                self.set_simple(local_index, &[0; 4], Some(&hash));
+            } else if let Some(leaf_hash) = sub_merkle.smt_single_leaf(info, path_index + 1) {
+                self.set_simple(local_index, &leaf_hash, Some(&hash));
+            } else {
+                Self::smt_store_sub_info(&sub_merkle.root(), info);
+                self.set(
+                    local_index,
+                    &tree_node_data(&sub_merkle.root()),
+                    true,
+                    Some(&hash),
+                );
+            }
+            true
+        }
+    }
+
+    // walks the path of the key and returns the content of the terminal leaf, collecting
+    // the tree nodes on the way and the siblings of the path in the merkle of each level
+    fn smt_terminal_local(
+        &self,
+        key: &[u64; 4],
+        path_index: usize,
+        nodes: &mut Vec<Vec<u64>>,
+        siblings: &mut Vec<[[u64; 4]; 32]>,
+    ) -> Vec<u64>
+    where
+        Self: MerklePath,
+    {
+        unsafe { require(path_index < 8) };
+        let local_index = (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+        let (_, content) = self.get(local_index, true);
+        siblings.push(self.siblings(local_index));
+        if !content.is_empty() && (content[0] & 0x1) == TREE_NODE {
+            let sub_merkle = Self::load(content[1..5].try_into().unwrap());
+            nodes.push(content);
+            sub_merkle.smt_terminal_local(key, path_index + 1, nodes, siblings)
+        } else {
+            content
+        }
+    }
+
+    fn smt_non_membership_local(&self, key: &[u64; 4]) -> Option<NonMembershipWitness>
+    where
+        Self: MerklePath,
+    {
+        let mut nodes = vec![];
+        let mut siblings = vec![];
+        let terminal = self.smt_terminal_local(key, 0, &mut nodes, &mut siblings);
+        if !terminal.is_empty() && data_matches_key(&terminal, key) {
+            None
+        } else {
+            Some(NonMembershipWitness {
+                nodes,
+                terminal,
+                siblings,
+            })
+        }
+    }
+
+    // Set distinct keys which share the path above this merkle. Keys are grouped by their
+    // leaf in this merkle, so each leaf (and the sub merkle behind it) is updated once.
+    // Returns the number and the sum of the keys that were not in the tree before.
+    fn smt_set_many_local(
+        &mut self,
+        entries: &mut [([u64; 4], &[u64])],
+        path_index: usize,
+    ) -> (u64, [u64; 4]) {
+        unsafe { require(path_index < 8) };
+        let local_index = |key: &[u64; 4]| (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+        entries.sort_by_key(|(key, _)| local_index(key));
+        let mut inserted = (0, [0; 4]);
+        let mut rest = entries;
+        while !rest.is_empty() {
+            let index = local_index(&rest[0].0);
+            let len = rest
+                .iter()
+                .take_while(|(key, _)| local_index(key) == index)
+                .count();
+            let (group, tail) = rest.split_at_mut(len);
+            rest = tail;
+            if group.len() == 1 {
+                let (key, data) = group[0];
+                if self.smt_set_local(&key, path_index, data) {
+                    inserted = (inserted.0 + 1, key_sum_add(&inserted.1, &key));
+                }
+                continue;
+            }
+            let (hash, content) = self.get(index, true);
+            let node_buf = if !content.is_empty() && (content[0] & 0x1) == TREE_NODE {
+                let sub_root: [u64; 4] = content[1..5].try_into().unwrap();
+                let mut sub_merkle = Self::load(sub_root);
+                let (n, sum) = sub_merkle.smt_set_many_local(group, path_index + 1);
+                inserted = (inserted.0 + n, key_sum_add(&inserted.1, &sum));
+                let info = Self::smt_sub_info(&sub_root)
+                    .map(|(count, key_sum)| (count + n, key_sum_add(&key_sum, &sum)));
+                Self::smt_store_sub_info(&sub_merkle.root(), info);
+                tree_node_data(&sub_merkle.root())
+            } else {
+                // the slot is empty or holds the leaf of a single key, which moves into the
+                // new sub merkle together with the group unless the group updates it
+                let mut sub_merkle = Self::new();
+                let mut count = 0;
+                let mut key_sum = [0; 4];
+                let mut existing = None;
+                if !content.is_empty() {
+                    let key: [u64; 4] = content[1..5].try_into().unwrap();
+                    if !group.iter().any(|(k, _)| *k == key) {
+                        sub_merkle.smt_set_local(&key, path_index + 1, &content[5..]);
+                        count = 1;
+                        key_sum = key;
                     }
-                } else {
-                    //crate::dbg!("key not match, creating sub node:\n");
-                    // conflict of key here
-                    // 1. start a new merkle sub tree
-                    let mut sub_merkle = Merkle::new();
-                    sub_merkle.smt_set_local(
-                        &content[1..5],
-                        path_index + 1,
-                        &content[5..content.len() as usize],
-                    );
-                    sub_merkle.smt_set_local(key, path_index + 1, data);
-                    let node_buf = set_smt_data(TREE_NODE, sub_merkle.root.as_slice(), &[]);
-                    // 2 update the current node with the sub merkle tree
-                    // crate::dbg!("created sub node {:?}:\n", node_buf);
-                    // OPT: shoulde be able to use the hint_hash in the future
-                    self.set(local_index, &node_buf[0..5], true, None);
+                    existing = Some(key);
                 }
+                let (n, sum) = sub_merkle.smt_set_many_local(group, path_index + 1);
+                count += n;
+                key_sum = key_sum_add(&key_sum, &sum);
+                // all the keys of the sub merkle but the existing one are new to the tree
+                let new = match existing {
+                    Some(key) => (count - 1, key_sum_sub(&key_sum, &key)),
+                    None => (count, key_sum),
+                };
+                inserted = (inserted.0 + new.0, key_sum_add(&inserted.1, &new.1));
+                Self::smt_store_sub_info(&sub_merkle.root(), Some((count, key_sum)));
+                tree_node_data(&sub_merkle.root())
+            };
+            self.set(index, &node_buf, true, Some(&hash));
+        }
+        inserted
+    }
+
+    // Get the values of the keys which share the path above this merkle, each leaf of
+    // this merkle is read once. Values are written to out at the position given with the key.
+    fn smt_get_many_local(
+        &self,
+        keys: &mut [(usize, [u64; 4])],
+        path_index: usize,
+        out: &mut [Vec<u64>],
+    ) {
+        unsafe { require(path_index < 8) };
+        let local_index = |key: &[u64; 4]| (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+        keys.sort_by_key(|(_, key)| local_index(key));
+        let mut rest = keys;
+        while !rest.is_empty() {
+            let index = local_index(&rest[0].1);
+            let len = rest
+                .iter()
+                .take_while(|(_, key)| local_index(key) == index)
+                .count();
+            let (group, tail) = rest.split_at_mut(len);
+            rest = tail;
+            let (_, content) = self.get(index, true);
+            if content.is_empty() {
+                continue;
+            }
+            if (content[0] & 0x1) == TREE_NODE {
+                let sub_merkle = Self::load(content[1..5].try_into().unwrap());
+                sub_merkle.smt_get_many_local(group, path_index + 1, out);
             } else {
-                //crate::dbg!("current node for set is node:\n");
-                // the node is already a sub merkle
-                unsafe { require((content[0] & 0x1) == TREE_NODE) };
-                let mut sub_merkle = Merkle::load(content[1..5].try_into().unwrap());
-                sub_merkle.smt_set_local(key, path_index + 1, data);
-                let node_buf = set_smt_data(TREE_NODE, sub_merkle.root.as_slice(), &[]);
-                self.set(local_index, &node_buf[0..5], true, None);
+                for (pos, key) in group.iter() {
+                    if data_matches_key(&content, key) {
+                        out[*pos] = content[5..].to_vec();
+                    }
+                }
+            }
+        }
+    }
+
+    fn smt_set_many_entries(&mut self, entries: &[([u64; 4], &[u64])]) {
+        // the sort is stable, so the entries of a key keep their order and the last one wins
+        let mut sorted = entries.to_vec();
+        sorted.sort_by_key(|(key, _)| *key);
+        let mut distinct: Vec<([u64; 4], &[u64])> = Vec::with_capacity(sorted.len());
+        for (key, data) in sorted {
+            match distinct.last_mut() {
+                Some(last) if last.0 == key => last.1 = data,
+                _ => distinct.push((key, data)),
             }
         }
+        self.smt_set_many_local(&mut distinct, 0);
     }
+
+    fn smt_get_many_entries(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+        let mut out = vec![vec![]; keys.len()];
+        let mut keys: Vec<(usize, [u64; 4])> = keys.iter().copied().enumerate().collect();
+        self.smt_get_many_local(&mut keys, 0, &mut out);
+        out
+    }
+}
+
+impl<M: MerkleBackend> SmtLocal for M {}
+
+// one level of the path of a key: the merkle of that level and the leaf it holds
+pub(crate) struct SmtStep<M> {
+    merkle: M,
+    index: u32,
+    hash: [u64; 4],
+    content: Vec<u64>,
 }
 
-impl SMT for Merkle {
-    fn smt_get(&self, key: &[u64; 4]) -> Vec<u64> {
-        self.smt_get_local(key, 0)
+impl<M: MerkleBackend> SmtStep<M> {
+    // write the leaf of the step with the leaf read by the walk as hint, returns the new root
+    fn write(mut self, data: &[u64]) -> [u64; 4] {
+        self.merkle.set(self.index, data, true, Some(&self.hash));
+        self.merkle.root()
     }
+}
+
+/// Entry of a key in a `KeyValueMap` for read-modify-write updates. The path of the key
+/// is walked once when the entry is created and the node data read there is reused by
+/// the write, which passes each leaf it read as the `set_simple` hint.
+pub struct Entry<'a, M: MerkleBackend + SMT> {
+    map: &'a mut KeyValueMap<M>,
+    key: [u64; 4],
+    steps: Vec<SmtStep<M>>,
+    value: Option<Vec<u64>>,
+}
 
-    fn smt_set(&mut self, key: &[u64; 4], data: &[u64]) {
-        self.smt_set_local(key, 0, data)
+impl<M: MerkleBackend + SMT> KeyValueMap<M> {
+    pub fn entry(&mut self, key: &[u64; 4]) -> Entry<'_, M> {
+        let steps = self.merkle.smt_walk(key, 0);
+        let terminal = &steps[steps.len() - 1].content;
+        let value = if !terminal.is_empty() && data_matches_key(terminal, key) {
+            Some(terminal[5..].to_vec())
+        } else {
+            None
+        };
+        Entry {
+            map: self,
+            key: *key,
+            steps,
+            value,
+        }
+    }
+}
+
+impl<'a, M: MerkleBackend + SMT> Entry<'a, M> {
+    pub fn key(&self) -> &[u64; 4] {
+        &self.key
+    }
+
+    /// Current value, or None if the key is absent
+    pub fn get(&self) -> Option<&[u64]> {
+        self.value.as_deref()
+    }
+
+    fn write(&mut self, data: Vec<u64>) {
+        let steps = if self.steps.is_empty() {
+            // the path read by the entry was consumed by a previous write
+            self.map.merkle.smt_walk(&self.key, 0)
+        } else {
+            std::mem::take(&mut self.steps)
+        };
+        let (root, _) = M::smt_write_path(steps, &self.key, &data, 0);
+        self.map.merkle = M::load(root);
+        self.value = Some(data);
+    }
+
+    /// Insert the default value if the key is absent and return the value of the key
+    pub fn or_insert(mut self, default: &[u64]) -> Vec<u64> {
+        if self.value.is_none() {
+            self.write(default.to_vec());
+        }
+        self.value.unwrap()
+    }
+
+    /// Modify the value in place if the key is present
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Vec<u64>)) -> Self {
+        if let Some(mut value) = self.value.take() {
+            f(&mut value);
+            self.write(value);
+        }
+        self
+    }
+
+    /// Replace the value (None if absent) with the result of f
+    pub fn update(mut self, f: impl FnOnce(Option<&[u64]>) -> Vec<u64>) {
+        let value = f(self.value.as_deref());
+        self.write(value);
+    }
+}
+
+#[cfg(feature = "native")]
+impl NonMembershipWitness {
+    /// Check that the key is absent from the map with the given root. Only the witness and
+    /// the merkle hash are used, so an external verifier needs nothing but the root.
+    pub fn verify(&self, root: &[u64; 4], key: &[u64; 4]) -> bool {
+        use crate::native::NativeHasher;
+        if self.nodes.len() >= 8 || self.siblings.len() != self.nodes.len() + 1 {
+            return false;
+        }
+        let local_index =
+            |path_index: usize| (key[path_index / 2] >> (32 * (path_index % 2))) as u32;
+        let mut root = *root;
+        for (path_index, node) in self.nodes.iter().enumerate() {
+            if node.len() != 5 || (node[0] & 0x1) != TREE_NODE {
+                return false;
+            }
+            let leaf = NativeHasher::hash(node, true);
+            let siblings = &self.siblings[path_index];
+            if NativeHasher::hash_path(&leaf, local_index(path_index), siblings) != root {
+                return false;
+            }
+            root = node[1..5].try_into().unwrap();
+        }
+        let path_index = self.nodes.len();
+        let leaf = if self.terminal.is_empty() {
+            [0; 4]
+        } else if self.terminal.len() >= 5
+            && (self.terminal[0] & 0x1) == LEAF_NODE
+            && !data_matches_key(&self.terminal, key)
+        {
+            NativeHasher::hash(&self.terminal, true)
+        } else {
+            return false;
+        };
+        let siblings = &self.siblings[path_index];
+        NativeHasher::hash_path(&leaf, local_index(path_index), siblings) == root
     }
 }
 
@@ -280,12 +982,20 @@ fn is_empty(a: u64) -> bool {
     (a & IS_EMPTY_BIT) == 0
 }
 
-impl Merkle {
-    // optimized version for
-    fn smt_get_local_u64(&self, key: u64, path_index: usize) -> u64 {
-        //crate::dbg!("start smt_get_local {}\n", path_index);
-        unsafe { require(path_index < 2) };
-        let local_index = (key >> (32 * (path_index % 2))) as u32;
+/// index of the key in the merkle of the given level, each level takes 32 bits of the
+/// key starting from the lowest bits of the first limb
+fn compact_local_index(key: &[u64], level: usize) -> u32 {
+    (key[level / 2] >> (32 * (level % 2))) as u32
+}
+
+// The SMT of `KeyValueMapU64` and `CompactKeyValueMap` written against `MerkleBackend`
+pub(crate) trait SmtCompactLocal: MerkleBackend {
+    // optimized version for keys of one or two limbs, where the key and the value are
+    // kept in the leaf itself as [key limbs.., value limbs.., flags]
+    fn smt_get_local_compact(&self, key: &[u64], level: usize) -> Option<[u64; 4]> {
+        //crate::dbg!("start smt_get_local {}\n", level);
+        unsafe { require(level < 2 * key.len()) };
+        let local_index = compact_local_index(key, level);
         // pad is true since the leaf might the root of a sub merkle
         let mut stored_data = [0; 4];
         self.get_simple(local_index, &mut stored_data);
@@ -294,28 +1004,31 @@ impl Merkle {
         if is_leaf {
             // second highest bit indicates the leaf node is empty or not
             let is_empty = is_empty(stored_data[3]);
-            let stored_key = stored_data[0];
-            if (!is_empty) && key == stored_key {
-                return stored_data[1];
+            if (!is_empty) && key == &stored_data[0..key.len()] {
+                Some(stored_data)
             } else {
                 // is empty or not hit
-                return 0;
+                None
             }
         } else {
             //crate::dbg!("smt_get_local is node: continue in sub merkle\n");
-            // make sure that there are only 2 level
+            // make sure that the key has bits left for the next level
             unsafe {
-                crate::require(path_index == 0);
+                crate::require(level + 1 < 2 * key.len());
             }
-            stored_data[3] = stored_data[3] & !IS_NODE_BIT;
-            let sub_merkle = Merkle::load(stored_data);
-            sub_merkle.smt_get_local_u64(key, path_index + 1)
+            stored_data[3] &= !IS_NODE_BIT;
+            let sub_merkle = Self::load(stored_data);
+            sub_merkle.smt_get_local_compact(key, level + 1)
         }
     }
 
-    fn smt_set_local_u64(&mut self, key: u64, path_index: usize, data: u64) {
-        unsafe { require(path_index < 2) };
-        let local_index = (key >> (32 * path_index)) as u32;
+    // leaf is the full leaf [key limbs.., value limbs.., IS_EMPTY_BIT] to store
+    fn smt_set_local_compact(&mut self, leaf: &[u64; 4], key_limbs: usize, level: usize) {
+        let key = &leaf[0..key_limbs];
+        unsafe { require(level < 2 * key_limbs) };
+        let local_index = compact_local_index(key, level);
+        // the get is the get of the get/set pair for the leaf writes below, while the
+        // stored data is passed as hint to the writes that happen after a sub merkle update
         let mut stored_data = [0; 4];
         self.get_simple(local_index, &mut stored_data);
         let is_leaf = is_leaf(stored_data[3]);
@@ -323,49 +1036,159 @@ impl Merkle {
         // LEAF_NODE must equal zero
         if is_leaf {
             let is_empty = is_empty(stored_data[3]);
-            if is_empty {
-                self.set_simple(local_index, &[key, data, 0, IS_EMPTY_BIT], None);
+            if is_empty || key == &stored_data[0..key_limbs] {
+                //crate::dbg!("current node for set is leaf:\n");
+                unsafe {
+                    self.set_simple_unsafe(local_index, leaf);
+                }
             } else {
-                //crate::dbg!("smt set local hit:\n");
-                if key == stored_data[0] {
-                    //crate::dbg!("current node for set is leaf:\n");
-                    stored_data[0] = key;
-                    stored_data[1] = data;
-                    self.set_simple(local_index, &stored_data, None);
-                } else {
-                    //crate::dbg!("key not match, creating sub node:\n");
-                    // conflict of key here
-                    // 1. start a new merkle sub tree
-                    let mut sub_merkle = Merkle::new();
-                    sub_merkle.smt_set_local_u64(stored_data[0], path_index + 1, stored_data[1]);
-                    sub_merkle.smt_set_local_u64(key, path_index + 1, data);
-                    stored_data = sub_merkle.root;
-                    stored_data[3] = stored_data[3] | IS_NODE_BIT;
-                    // 2 update the current node with the sub merkle tree
-                    self.set_simple(local_index, &stored_data, None);
+                //crate::dbg!("key not match, creating sub node:\n");
+                // conflict of key here, make sure that there is a next level
+                unsafe {
+                    crate::require(level + 1 < 2 * key_limbs);
                 }
+                // 1. start a new merkle sub tree
+                let mut sub_merkle = Self::new();
+                sub_merkle.smt_set_local_compact(&stored_data, key_limbs, level + 1);
+                sub_merkle.smt_set_local_compact(leaf, key_limbs, level + 1);
+                let mut node = sub_merkle.root();
+                node[3] |= IS_NODE_BIT;
+                // 2 update the current node with the sub merkle tree
+                self.set_simple(local_index, &node, Some(&stored_data));
             }
         } else {
             //crate::dbg!("current node for set is node:\n");
-            // make sure that there are only 2 level
             unsafe {
-                crate::require(path_index == 0);
+                crate::require(level + 1 < 2 * key_limbs);
             }
-            stored_data[3] = stored_data[3] & !IS_NODE_BIT;
-            let mut sub_merkle = Merkle::load(stored_data);
-            sub_merkle.smt_set_local_u64(key, path_index + 1, data);
-            sub_merkle.root[3] = sub_merkle.root[3] | IS_NODE_BIT;
-            self.set_simple(local_index, &sub_merkle.root, None);
+            let mut sub_root = stored_data;
+            sub_root[3] &= !IS_NODE_BIT;
+            let mut sub_merkle = Self::load(sub_root);
+            sub_merkle.smt_set_local_compact(leaf, key_limbs, level + 1);
+            let mut node = sub_merkle.root();
+            node[3] |= IS_NODE_BIT;
+            self.set_simple(local_index, &node, Some(&stored_data));
         }
     }
-}
 
-impl SMTU64 for Merkle {
-    fn smt_get(&self, key: u64) -> u64 {
-        self.smt_get_local_u64(key, 0)
+    fn smt_remove_local_compact(&mut self, key: &[u64], level: usize) -> bool {
+        unsafe { require(level < 2 * key.len()) };
+        let local_index = compact_local_index(key, level);
+        let mut stored_data = [0; 4];
+        self.get_simple(local_index, &mut stored_data);
+        if is_leaf(stored_data[3]) {
+            if is_empty(stored_data[3]) || key != &stored_data[0..key.len()] {
+                return false;
+            }
+            unsafe {
+                self.set_simple_unsafe(local_index, &[0; 4]);
+            }
+            true
+        } else {
+            unsafe {
+                crate::require(level + 1 < 2 * key.len());
+            }
+            let mut sub_root = stored_data;
+            sub_root[3] &= !IS_NODE_BIT;
+            let mut sub_merkle = Self::load(sub_root);
+            if !sub_merkle.smt_remove_local_compact(key, level + 1) {
+                return false;
+            }
+            // a sub merkle left without keys goes back to an empty leaf, while one left
+            // with a single key is kept since the leaves do not record how many keys
+            // a sub merkle holds
+            let node = if sub_merkle.root() == Self::new().root() {
+                [0; 4]
+            } else {
+                let mut node = sub_merkle.root();
+                node[3] |= IS_NODE_BIT;
+                node
+            };
+            self.set_simple(local_index, &node, Some(&stored_data));
+            true
+        }
     }
 
-    fn smt_set(&mut self, key: u64, data: u64) {
-        self.smt_set_local_u64(key, 0, data)
+    fn smt_get_compact<K: SmtKey>(&self, key: &K) -> Option<Vec<u64>> {
+        let key = key.to_limbs();
+        self.smt_get_local_compact(&key[0..K::LIMBS], 0)
+            .map(|leaf| leaf[K::LIMBS..3].to_vec())
     }
+
+    fn smt_set_compact<K: SmtKey>(&mut self, key: &K, data: &[u64]) {
+        unsafe { require(data.len() == K::VALUE_LIMBS) };
+        let key = key.to_limbs();
+        let mut leaf = [0; 4];
+        leaf[0..K::LIMBS].copy_from_slice(&key[0..K::LIMBS]);
+        leaf[K::LIMBS..3].copy_from_slice(data);
+        leaf[3] = IS_EMPTY_BIT;
+        self.smt_set_local_compact(&leaf, K::LIMBS, 0)
+    }
+
+    fn smt_remove_compact<K: SmtKey>(&mut self, key: &K) -> bool {
+        let key = key.to_limbs();
+        self.smt_remove_local_compact(&key[0..K::LIMBS], 0)
+    }
+}
+
+impl<M: MerkleBackend> SmtCompactLocal for M {}
+
+// SMT, SMTU64 and SMTCompact for a `MerkleBackend`. They are implemented for each backend
+// since a blanket impl over `MerkleBackend` would overlap with `impl SMT for &mut S`.
+macro_rules! impl_smt_for_backend {
+    ($backend:ty) => {
+        impl $crate::kvpair::SMT for $backend {
+            fn smt_get(&self, key: &[u64; 4]) -> Vec<u64> {
+                $crate::merkle::SmtLocal::smt_get_local(self, key, 0)
+            }
+
+            fn smt_set(&mut self, key: &[u64; 4], data: &[u64]) {
+                $crate::merkle::SmtLocal::smt_set_local(self, key, 0, data);
+            }
+
+            fn smt_remove(&mut self, key: &[u64; 4]) -> bool {
+                $crate::merkle::SmtLocal::smt_remove_local(self, key, 0)
+            }
+
+            fn smt_set_many(&mut self, entries: &[([u64; 4], &[u64])]) {
+                $crate::merkle::SmtLocal::smt_set_many_entries(self, entries)
+            }
+
+            fn smt_get_many(&self, keys: &[[u64; 4]]) -> Vec<Vec<u64>> {
+                $crate::merkle::SmtLocal::smt_get_many_entries(self, keys)
+            }
+        }
+
+        impl<K: $crate::kvpair::SmtKey> $crate::kvpair::SMTCompact<K> for $backend {
+            fn smt_get(&self, key: &K) -> Option<Vec<u64>> {
+                $crate::merkle::SmtCompactLocal::smt_get_compact(self, key)
+            }
+
+            fn smt_set(&mut self, key: &K, data: &[u64]) {
+                $crate::merkle::SmtCompactLocal::smt_set_compact(self, key, data)
+            }
+
+            fn smt_remove(&mut self, key: &K) -> bool {
+                $crate::merkle::SmtCompactLocal::smt_remove_compact(self, key)
+            }
+        }
+
+        impl $crate::kvpair::SMTU64 for $backend {
+            fn smt_get(&self, key: u64) -> Option<[u64; 2]> {
+                $crate::merkle::SmtCompactLocal::smt_get_compact(self, &key)
+                    .map(|data| [data[0], data[1]])
+            }
+
+            fn smt_set(&mut self, key: u64, data: &[u64; 2]) {
+                $crate::merkle::SmtCompactLocal::smt_set_compact(self, &key, data)
+            }
+
+            fn smt_remove(&mut self, key: u64) -> bool {
+                $crate::merkle::SmtCompactLocal::smt_remove_compact(self, &key)
+            }
+        }
+    };
 }
+pub(crate) use impl_smt_for_backend;
+
+impl_smt_for_backend!(Merkle);
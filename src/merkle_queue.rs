@@ -0,0 +1,101 @@
+use crate::codec::LeafCodec;
+use crate::merkle::Merkle;
+use crate::require;
+use std::marker::PhantomData;
+
+/// Reserved leaf which keeps the head and tail counters
+const POINTER_INDEX: u32 = u32::MAX;
+/// Number of slots, every leaf but the reserved one
+const CAPACITY: u64 = u32::MAX as u64;
+
+/// FIFO queue of typed elements on top of a merkle, e.g. deposits queued in one proof
+/// and processed in a later one. The leaf `u32::MAX` keeps `[head, tail, 0, 0]`, where
+/// head and tail count the elements ever dequeued and enqueued, and the element with
+/// counter c is stored with `Merkle::set` in the slot `c % (2^32 - 1)`. Slots are
+/// cleared on dequeue and reused, so the queue runs forever as long as it never holds
+/// more than 2^32 - 1 elements at once.
+pub struct MerkleQueue<T: LeafCodec> {
+    pub merkle: Merkle,
+    _marker: PhantomData<T>,
+}
+
+impl<T: LeafCodec> Default for MerkleQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: LeafCodec> MerkleQueue<T> {
+    pub fn new() -> Self {
+        Self::load(Merkle::new().root)
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        MerkleQueue {
+            merkle: Merkle::load(root),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn root(&self) -> [u64; 4] {
+        self.merkle.root
+    }
+
+    fn pointers(&self) -> [u64; 4] {
+        let mut pointers = [0; 4];
+        self.merkle.get_simple(POINTER_INDEX, &mut pointers);
+        pointers
+    }
+
+    fn slot(counter: u64) -> u32 {
+        (counter % CAPACITY) as u32
+    }
+
+    pub fn len(&self) -> u64 {
+        let pointers = self.pointers();
+        pointers[1] - pointers[0]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn enqueue(&mut self, value: &T) {
+        let pointers = self.pointers();
+        let (head, tail) = (pointers[0], pointers[1]);
+        let data = value.to_limbs();
+        unsafe {
+            require(!data.is_empty());
+            require(tail - head < CAPACITY);
+        }
+        // the slot was cleared by the dequeue of its previous element
+        self.merkle
+            .set(Self::slot(tail), &data, true, Some(&[0; 4]));
+        self.merkle
+            .set_simple(POINTER_INDEX, &[head, tail + 1, 0, 0], Some(&pointers));
+    }
+
+    /// Remove and return the oldest element, or None if the queue is empty
+    pub fn dequeue(&mut self) -> Option<T> {
+        let pointers = self.pointers();
+        let (head, tail) = (pointers[0], pointers[1]);
+        if head == tail {
+            return None;
+        }
+        let (hash, data) = self.merkle.get(Self::slot(head), true);
+        self.merkle.remove(Self::slot(head), Some(&hash));
+        self.merkle
+            .set_simple(POINTER_INDEX, &[head + 1, tail, 0, 0], Some(&pointers));
+        Some(T::from_limbs(&data))
+    }
+
+    /// The oldest element, or None if the queue is empty
+    pub fn peek(&self) -> Option<T> {
+        let pointers = self.pointers();
+        if pointers[0] == pointers[1] {
+            return None;
+        }
+        let (_, data) = self.merkle.get(Self::slot(pointers[0]), true);
+        Some(T::from_limbs(&data))
+    }
+}
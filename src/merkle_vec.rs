@@ -0,0 +1,122 @@
+use crate::codec::LeafCodec;
+use crate::merkle::Merkle;
+use crate::require;
+use std::marker::PhantomData;
+
+/// Reserved leaf which keeps the number of elements
+const LENGTH_INDEX: u32 = u32::MAX;
+
+/// Growable vector of typed elements on top of a merkle. Element i is stored with
+/// `Merkle::set` at index i and the length is kept in the last leaf as `[len, 0, 0, 0]`.
+///
+/// The root is the whole state of the vector, so a vector can be nested in the value
+/// of a `KeyValueMap` by storing `root()` and reopened with `load`.
+pub struct MerkleVec<T: LeafCodec> {
+    pub merkle: Merkle,
+    _marker: PhantomData<T>,
+}
+
+impl<T: LeafCodec> Default for MerkleVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: LeafCodec> MerkleVec<T> {
+    pub fn new() -> Self {
+        Self::load(Merkle::new().root)
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        MerkleVec {
+            merkle: Merkle::load(root),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn root(&self) -> [u64; 4] {
+        self.merkle.root
+    }
+
+    pub fn len(&self) -> u32 {
+        let mut leaf = [0; 4];
+        self.merkle.get_simple(LENGTH_INDEX, &mut leaf);
+        leaf[0] as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn set_len(&mut self, old: u32, new: u32) {
+        self.merkle.set_simple(
+            LENGTH_INDEX,
+            &[new as u64, 0, 0, 0],
+            Some(&[old as u64, 0, 0, 0]),
+        );
+    }
+
+    /// Append the value and return its index
+    pub fn push(&mut self, value: &T) -> u32 {
+        let index = self.len();
+        let data = value.to_limbs();
+        unsafe {
+            require(!data.is_empty());
+            require(index < LENGTH_INDEX);
+        }
+        // slots past the end are always empty
+        self.merkle.set(index, &data, true, Some(&[0; 4]));
+        self.set_len(index, index + 1);
+        index
+    }
+
+    /// Remove and return the last element, or None if the vector is empty
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let (hash, data) = self.merkle.get(len - 1, true);
+        self.merkle.remove(len - 1, Some(&hash));
+        self.set_len(len, len - 1);
+        Some(T::from_limbs(&data))
+    }
+
+    /// Returns None if the index is past the end
+    pub fn get(&self, index: u32) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        let (_, data) = self.merkle.get(index, true);
+        Some(T::from_limbs(&data))
+    }
+
+    /// Replace the element at index, which must be less than the length
+    pub fn set(&mut self, index: u32, value: &T) {
+        let data = value.to_limbs();
+        unsafe {
+            require(!data.is_empty());
+            require(index < self.len());
+        }
+        self.merkle.set(index, &data, true, None);
+    }
+
+    /// Remove the element at index and move the last element into its place
+    pub fn swap_remove(&mut self, index: u32) -> T {
+        let len = self.len();
+        unsafe { require(index < len) };
+        let (hash, data) = self.merkle.get(index, true);
+        let last = len - 1;
+        if index != last {
+            // the preimage of the last element is already in the cache
+            let mut last_hash = [0; 4];
+            self.merkle.get_simple(last, &mut last_hash);
+            self.merkle.set_simple(index, &last_hash, Some(&hash));
+            self.merkle.set_simple(last, &[0; 4], Some(&last_hash));
+        } else {
+            self.merkle.set_simple(last, &[0; 4], Some(&hash));
+        }
+        self.set_len(len, last);
+        T::from_limbs(&data)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::history::RootHistory;
+use crate::merkle::Merkle;
+use crate::poseidon::PoseidonHasher;
+use crate::require;
+
+/// Reserved leaf of the peak merkle which keeps the number of appended leaves
+const COUNT_INDEX: u32 = u32::MAX;
+
+/// Domain tags prefixed to the hashed limbs, so that the preimage of a leaf can never be
+/// read as the preimage of an internal node
+const LEAF_TAG: u64 = 0;
+const NODE_TAG: u64 = 1;
+
+/// Merkle mountain range accumulator for histories of unbounded length.
+///
+/// Only the peaks are kept in provable state: the peak of height h is stored as a
+/// raw leaf at index h of a small `Merkle` and an empty leaf means there is no peak
+/// of that height. Inclusion proofs are built by whoever keeps the appended data
+/// (e.g. an indexer) and verified in-guest with `verify` or `verify_root`.
+pub struct MerkleMountainRange {
+    pub peaks: Merkle,
+}
+
+/// Inclusion proof of the leaf at `index`, the siblings go from the leaf up to its peak
+pub struct MmrProof {
+    pub index: u64,
+    pub siblings: Vec<[u64; 4]>,
+}
+
+impl Default for MerkleMountainRange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleMountainRange {
+    pub fn new() -> Self {
+        MerkleMountainRange {
+            peaks: Merkle::new(),
+        }
+    }
+
+    pub fn load(root: [u64; 4]) -> Self {
+        MerkleMountainRange {
+            peaks: Merkle::load(root),
+        }
+    }
+
+    pub fn leaf_hash(data: &[u64]) -> [u64; 4] {
+        let mut buf = Vec::with_capacity(data.len() + 1);
+        buf.push(LEAF_TAG);
+        buf.extend_from_slice(data);
+        PoseidonHasher::hash(&buf, true)
+    }
+
+    /// The limbs are padded since the tag shifts the hash limbs out of their field elements
+    pub fn node_hash(left: &[u64; 4], right: &[u64; 4]) -> [u64; 4] {
+        PoseidonHasher::hash(
+            &[
+                NODE_TAG, left[0], left[1], left[2], left[3], right[0], right[1], right[2],
+                right[3],
+            ],
+            true,
+        )
+    }
+
+    /// Number of appended leaves
+    pub fn count(&self) -> u64 {
+        let mut leaf = [0; 4];
+        self.peaks.get_simple(COUNT_INDEX, &mut leaf);
+        leaf[0]
+    }
+
+    pub fn peak(&self, height: u32) -> [u64; 4] {
+        let mut peak = [0; 4];
+        self.peaks.get_simple(height, &mut peak);
+        peak
+    }
+
+    /// Append a leaf and return its index
+    pub fn append(&mut self, data: &[u64]) -> u64 {
+        let count = self.count();
+        unsafe { require(count < u64::MAX) };
+        let mut hash = Self::leaf_hash(data);
+        let mut height = 0;
+        // merge the peaks of equal height like a binary counter
+        while (count >> height) & 1 == 1 {
+            let peak = self.peak(height);
+            hash = Self::node_hash(&peak, &hash);
+            self.peaks.set_simple(height, &[0; 4], Some(&peak));
+            height += 1;
+        }
+        self.peaks.set_simple(height, &hash, Some(&[0; 4]));
+        self.peaks
+            .set_simple(COUNT_INDEX, &[count + 1, 0, 0, 0], Some(&[count, 0, 0, 0]));
+        count
+    }
+
+    /// Peaks from the highest to the lowest
+    pub fn peak_list(&self) -> Vec<[u64; 4]> {
+        let count = self.count();
+        (0..64)
+            .rev()
+            .filter(|height| (count >> height) & 1 == 1)
+            .map(|height| self.peak(height))
+            .collect()
+    }
+
+    /// Single commitment of the whole range obtained by bagging the peaks
+    pub fn root(&self) -> [u64; 4] {
+        Self::bag_peaks(self.count(), &self.peak_list())
+    }
+
+    /// Bag the peaks (given from the highest to the lowest) and bind the leaf count
+    pub fn bag_peaks(count: u64, peaks: &[[u64; 4]]) -> [u64; 4] {
+        match peaks.split_last() {
+            None => [0; 4],
+            Some((lowest, higher)) => {
+                let bagged = higher
+                    .iter()
+                    .rev()
+                    .fold(*lowest, |acc, peak| Self::node_hash(peak, &acc));
+                PoseidonHasher::hash(&[count, bagged[0], bagged[1], bagged[2], bagged[3]], true)
+            }
+        }
+    }
+
+    /// Height of the peak that covers the leaf and the position of the leaf under it
+    fn locate(count: u64, index: u64) -> Option<(u32, u64)> {
+        let mut offset = 0;
+        for height in (0..64).rev() {
+            if (count >> height) & 1 == 1 {
+                let size = 1u64 << height;
+                if index - offset < size {
+                    return Some((height, index - offset));
+                }
+                offset += size;
+            }
+        }
+        None
+    }
+
+    /// Compute the peak covering the proven leaf and return it with its height
+    fn proof_peak(count: u64, data: &[u64], proof: &MmrProof) -> Option<(u32, [u64; 4])> {
+        if proof.index >= count {
+            return None;
+        }
+        let (height, mut position) = Self::locate(count, proof.index)?;
+        if proof.siblings.len() != height as usize {
+            return None;
+        }
+        let mut hash = Self::leaf_hash(data);
+        for sibling in proof.siblings.iter() {
+            hash = if position & 1 == 0 {
+                Self::node_hash(&hash, sibling)
+            } else {
+                Self::node_hash(sibling, &hash)
+            };
+            position >>= 1;
+        }
+        Some((height, hash))
+    }
+
+    /// Verify an inclusion proof against the peaks kept in provable state
+    pub fn verify(&self, data: &[u64], proof: &MmrProof) -> bool {
+        match Self::proof_peak(self.count(), data, proof) {
+            Some((height, peak)) => peak == self.peak(height),
+            None => false,
+        }
+    }
+
+    /// Verify an inclusion proof against a bagged root, which does not need the
+    /// range itself but all its peaks (from the highest to the lowest)
+    pub fn verify_root(
+        root: &[u64; 4],
+        count: u64,
+        peaks: &[[u64; 4]],
+        data: &[u64],
+        proof: &MmrProof,
+    ) -> bool {
+        if peaks.len() != count.count_ones() as usize || Self::bag_peaks(count, peaks) != *root {
+            return false;
+        }
+        match Self::proof_peak(count, data, proof) {
+            Some((height, peak)) => {
+                // peaks are listed from the highest, so count the higher peaks
+                let position = count.checked_shr(height + 1).unwrap_or(0).count_ones() as usize;
+                peaks[position] == peak
+            }
+            None => false,
+        }
+    }
+
+    /// Verify an inclusion proof against any of the recent bagged roots kept in history
+    pub fn verify_recent_root(
+        history: &RootHistory,
+        root: &[u64; 4],
+        count: u64,
+        peaks: &[[u64; 4]],
+        data: &[u64],
+        proof: &MmrProof,
+    ) -> bool {
+        history.is_known_root(root) && Self::verify_root(root, count, peaks, data, proof)
+    }
+}
@@ -0,0 +1,567 @@
+use crate::checkpoint::MerkleRoot;
+use crate::kvpair::{NonMembershipWitness, SMTWitness};
+use crate::merkle::{impl_smt_for_backend, MerkleBackend, MerklePath, SmtLocal};
+use crate::require;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Off-chain stand in for the host `require`, so that the SMT code shared with the
+/// guest links on native targets and panics when a check fails. The whole module is
+/// behind the opt-in `native` feature so that the symbol is only exported by builds
+/// which ask for it.
+#[cfg(not(target_arch = "wasm32"))]
+#[export_name = "require"]
+extern "C" fn native_require(cond: bool) {
+    assert!(cond, "require failed");
+}
+
+// BN254 scalar field, the field of the host Poseidon, as little endian limbs
+const MODULUS: [u64; 4] = [
+    0x43e1f593f0000001,
+    0x2833e84879b97091,
+    0xb85045b68181585d,
+    0x30644e72e131a029,
+];
+// -MODULUS^-1 mod 2^64
+const INV: u64 = 0xc2e1f593efffffff;
+// 2^512 mod MODULUS, converts into montgomery form
+const R2: [u64; 4] = [
+    0x1bb8e645ae216da7,
+    0x53fe3ab1e35c59e3,
+    0x8c49833d53bb8085,
+    0x0216d0b17f4e44a5,
+];
+
+fn adc(a: u64, b: u64, carry: u64) -> (u64, u64) {
+    let t = a as u128 + b as u128 + carry as u128;
+    (t as u64, (t >> 64) as u64)
+}
+
+fn mac(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    let t = a as u128 + (b as u128) * (c as u128) + carry as u128;
+    (t as u64, (t >> 64) as u64)
+}
+
+fn is_canonical(limbs: &[u64; 4]) -> bool {
+    for i in (0..4).rev() {
+        if limbs[i] != MODULUS[i] {
+            return limbs[i] < MODULUS[i];
+        }
+    }
+    false
+}
+
+fn sub_modulus(limbs: &[u64; 4]) -> [u64; 4] {
+    let mut r = [0; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        let t = (limbs[i] as u128).wrapping_sub(MODULUS[i] as u128 + borrow);
+        r[i] = t as u64;
+        borrow = t >> 127;
+    }
+    r
+}
+
+// element of the field in montgomery form
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Fr([u64; 4]);
+
+impl Fr {
+    const ZERO: Fr = Fr([0; 4]);
+
+    fn from_limbs(limbs: &[u64; 4]) -> Fr {
+        // the host rejects non canonical field elements as well
+        unsafe { require(is_canonical(limbs)) };
+        Fr(*limbs).mul(&Fr(R2))
+    }
+
+    fn to_limbs(self) -> [u64; 4] {
+        self.mul(&Fr([1, 0, 0, 0])).0
+    }
+
+    fn add(&self, other: &Fr) -> Fr {
+        let mut r = [0; 4];
+        let mut carry = 0;
+        for (i, limb) in r.iter_mut().enumerate() {
+            (*limb, carry) = adc(self.0[i], other.0[i], carry);
+        }
+        // the modulus leaves the top bit spare so the sum does not overflow
+        if !is_canonical(&r) {
+            r = sub_modulus(&r);
+        }
+        Fr(r)
+    }
+
+    fn mul(&self, other: &Fr) -> Fr {
+        // montgomery multiplication (CIOS)
+        let mut t = [0u64; 6];
+        for b in other.0 {
+            let mut carry = 0;
+            for (j, limb) in t.iter_mut().take(4).enumerate() {
+                (*limb, carry) = mac(*limb, self.0[j], b, carry);
+            }
+            (t[4], t[5]) = adc(t[4], carry, 0);
+            let m = t[0].wrapping_mul(INV);
+            (_, carry) = mac(t[0], m, MODULUS[0], 0);
+            for j in 1..4 {
+                (t[j - 1], carry) = mac(t[j], m, MODULUS[j], carry);
+            }
+            let c;
+            (t[3], c) = adc(t[4], carry, 0);
+            t[4] = t[5] + c;
+        }
+        let mut r = [t[0], t[1], t[2], t[3]];
+        if t[4] != 0 || !is_canonical(&r) {
+            r = sub_modulus(&r);
+        }
+        Fr(r)
+    }
+
+    fn pow5(&self) -> Fr {
+        let x2 = self.mul(self);
+        x2.mul(&x2).mul(self)
+    }
+
+    fn invert(&self) -> Fr {
+        // x^(p - 2)
+        let mut exp = MODULUS;
+        exp[0] -= 2;
+        let mut r = Fr::from_limbs(&[1, 0, 0, 0]);
+        for limb in exp.iter().rev() {
+            for bit in (0..64).rev() {
+                r = r.mul(&r);
+                if (limb >> bit) & 1 == 1 {
+                    r = r.mul(self);
+                }
+            }
+        }
+        r
+    }
+}
+
+// Grain LFSR of the Poseidon reference implementation, which generates the round
+// constants and the MDS matrix
+struct Grain {
+    state: Vec<bool>,
+}
+
+impl Grain {
+    fn new(t: usize, r_f: usize, r_p: usize) -> Self {
+        let mut state = vec![true; 80];
+        let mut set_bits = |offset: usize, len: usize, value: usize| {
+            for i in 0..len {
+                state[offset + len - 1 - i] = (value >> i) & 1 == 1;
+            }
+        };
+        set_bits(0, 2, 1); // prime field
+        set_bits(2, 4, 0); // x^alpha sbox
+        set_bits(6, 12, 254); // field size
+        set_bits(18, 12, t);
+        set_bits(30, 10, r_f);
+        set_bits(40, 10, r_p);
+        let mut grain = Grain { state };
+        for _ in 0..160 {
+            grain.next_raw_bit();
+        }
+        grain
+    }
+
+    fn next_raw_bit(&mut self) -> bool {
+        let s = &self.state;
+        let bit = s[62] ^ s[51] ^ s[38] ^ s[23] ^ s[13] ^ s[0];
+        self.state.remove(0);
+        self.state.push(bit);
+        bit
+    }
+
+    fn next_bit(&mut self) -> bool {
+        loop {
+            let control = self.next_raw_bit();
+            let bit = self.next_raw_bit();
+            if control {
+                return bit;
+            }
+        }
+    }
+
+    // 254 bits, most significant first
+    fn next_limbs(&mut self) -> [u64; 4] {
+        let mut limbs = [0; 4];
+        for i in (0..254).rev() {
+            if self.next_bit() {
+                limbs[i / 64] |= 1 << (i % 64);
+            }
+        }
+        limbs
+    }
+
+    fn next_field_element(&mut self) -> Fr {
+        loop {
+            let limbs = self.next_limbs();
+            if is_canonical(&limbs) {
+                return Fr::from_limbs(&limbs);
+            }
+        }
+    }
+
+    fn next_field_element_without_rejection(&mut self) -> Fr {
+        let mut limbs = self.next_limbs();
+        while !is_canonical(&limbs) {
+            limbs = sub_modulus(&limbs);
+        }
+        Fr::from_limbs(&limbs)
+    }
+}
+
+// Poseidon permutation with x^5 sbox over a state of t elements
+struct PoseidonSpec {
+    t: usize,
+    r_f: usize,
+    r_p: usize,
+    constants: Vec<Vec<Fr>>,
+    mds: Vec<Vec<Fr>>,
+}
+
+impl PoseidonSpec {
+    fn new(t: usize, r_f: usize, r_p: usize) -> Self {
+        let mut grain = Grain::new(t, r_f, r_p);
+        let constants = (0..r_f + r_p)
+            .map(|_| (0..t).map(|_| grain.next_field_element()).collect())
+            .collect();
+        let xs: Vec<Fr> = (0..t)
+            .map(|_| grain.next_field_element_without_rejection())
+            .collect();
+        let ys: Vec<Fr> = (0..t)
+            .map(|_| grain.next_field_element_without_rejection())
+            .collect();
+        // cauchy matrix
+        let mds = xs
+            .iter()
+            .map(|x| ys.iter().map(|y| x.add(y).invert()).collect())
+            .collect();
+        PoseidonSpec {
+            t,
+            r_f,
+            r_p,
+            constants,
+            mds,
+        }
+    }
+
+    fn permute(&self, state: &mut [Fr]) {
+        for (round, constants) in self.constants.iter().enumerate() {
+            for (s, c) in state.iter_mut().zip(constants) {
+                *s = s.add(c);
+            }
+            if round < self.r_f / 2 || round >= self.r_f / 2 + self.r_p {
+                for s in state.iter_mut() {
+                    *s = s.pow5();
+                }
+            } else {
+                state[0] = state[0].pow5();
+            }
+            let mixed: Vec<Fr> = self
+                .mds
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .zip(state.iter())
+                        .fold(Fr::ZERO, |acc, (m, s)| acc.add(&m.mul(s)))
+                })
+                .collect();
+            state.copy_from_slice(&mixed);
+        }
+    }
+
+    // The host sponge: the capacity starts at 2^64 and every chunk of t - 1 elements
+    // is added to the state and permuted, the padding is left to the caller
+    fn hash(&self, elements: &[Fr]) -> Fr {
+        unsafe { require(!elements.is_empty() && elements.len() % (self.t - 1) == 0) };
+        let mut state = vec![Fr::ZERO; self.t];
+        state[0] = Fr::from_limbs(&[0, 1, 0, 0]);
+        for chunk in elements.chunks(self.t - 1) {
+            for (s, e) in state[1..].iter_mut().zip(chunk) {
+                *s = s.add(e);
+            }
+            self.permute(&mut state);
+        }
+        state[1]
+    }
+}
+
+// hasher of PoseidonHasher
+fn data_spec() -> &'static PoseidonSpec {
+    static SPEC: OnceLock<PoseidonSpec> = OnceLock::new();
+    SPEC.get_or_init(|| PoseidonSpec::new(9, 8, 63))
+}
+
+// hasher of the merkle leaves and nodes
+fn merkle_spec() -> &'static PoseidonSpec {
+    static SPEC: OnceLock<PoseidonSpec> = OnceLock::new();
+    SPEC.get_or_init(|| PoseidonSpec::new(3, 8, 57))
+}
+
+/// Off-chain versions of the Poseidon hashes computed by the host
+pub struct NativeHasher;
+
+impl NativeHasher {
+    /// Same as `PoseidonHasher::hash`
+    pub fn hash(data: &[u64], padding: bool) -> [u64; 4] {
+        // the limbs pushed by PoseidonHasher, 32 limbs (8 field elements) per round
+        let mut limbs = vec![];
+        if padding {
+            for group in data.chunks(3) {
+                limbs.extend_from_slice(group);
+                if group.len() == 3 {
+                    limbs.push(0);
+                }
+            }
+        } else {
+            limbs.extend_from_slice(data);
+        }
+        limbs.resize((limbs.len() + 3) / 4 * 4, 0);
+        limbs.push(1);
+        limbs.resize((limbs.len() + 31) / 32 * 32, 0);
+        let elements: Vec<Fr> = limbs
+            .chunks(4)
+            .map(|l| Fr::from_limbs(l.try_into().unwrap()))
+            .collect();
+        data_spec().hash(&elements).to_limbs()
+    }
+
+    /// Hash of the raw data of a merkle leaf, each half of the leaf is a field element
+    pub fn hash_leaf(data: &[u64; 4]) -> [u64; 4] {
+        let lo = Fr::from_limbs(&[data[0], data[1], 0, 0]);
+        let hi = Fr::from_limbs(&[data[2], data[3], 0, 0]);
+        merkle_spec().hash(&[lo, hi]).to_limbs()
+    }
+
+    /// Hash of a merkle node
+    pub fn hash_node(left: &[u64; 4], right: &[u64; 4]) -> [u64; 4] {
+        merkle_spec()
+            .hash(&[Fr::from_limbs(left), Fr::from_limbs(right)])
+            .to_limbs()
+    }
+
+    /// Root of a merkle holding the raw data leaf at index, given the hashes of the
+    /// siblings on the path of the leaf from the leaf up
+    pub fn hash_path(leaf: &[u64; 4], index: u32, siblings: &[[u64; 4]; DEPTH]) -> [u64; 4] {
+        let mut node = Self::hash_leaf(leaf);
+        for (level, sibling) in siblings.iter().enumerate() {
+            node = if (index >> level) & 1 == 1 {
+                Self::hash_node(sibling, &node)
+            } else {
+                Self::hash_node(&node, sibling)
+            };
+        }
+        node
+    }
+}
+
+const DEPTH: usize = 32;
+
+// hash of the empty sub tree of each height, the empty leaf holds zeros
+fn default_hashes() -> &'static [[u64; 4]; DEPTH + 1] {
+    static HASHES: OnceLock<[[u64; 4]; DEPTH + 1]> = OnceLock::new();
+    HASHES.get_or_init(|| {
+        let mut hashes = [NativeHasher::hash_leaf(&[0; 4]); DEPTH + 1];
+        for height in 1..=DEPTH {
+            hashes[height] = NativeHasher::hash_node(&hashes[height - 1], &hashes[height - 1]);
+        }
+        hashes
+    })
+}
+
+// Content addressed node store shared by all native merkles, like the merkle db and
+// the data cache of the host. Nodes are never dropped, so any root computed in the
+// thread can be loaded again.
+#[derive(Default)]
+struct NativeDb {
+    nodes: HashMap<[u64; 4], ([u64; 4], [u64; 4])>,
+    leaves: HashMap<[u64; 4], [u64; 4]>,
+    data: HashMap<[u64; 4], Vec<u64>>,
+    hints: HashMap<[u64; 4], Vec<u64>>,
+}
+
+thread_local! {
+    static DB: RefCell<NativeDb> = RefCell::new(NativeDb::default());
+}
+
+impl NativeDb {
+    fn children(&self, hash: &[u64; 4], height: usize) -> ([u64; 4], [u64; 4]) {
+        let default = default_hashes();
+        if *hash == default[height] {
+            return (default[height - 1], default[height - 1]);
+        }
+        let children = self.nodes.get(hash).copied();
+        unsafe { require(children.is_some()) };
+        children.unwrap()
+    }
+
+    fn leaf(&self, hash: &[u64; 4]) -> [u64; 4] {
+        if *hash == default_hashes()[0] {
+            return [0; 4];
+        }
+        let leaf = self.leaves.get(hash).copied();
+        unsafe { require(leaf.is_some()) };
+        leaf.unwrap()
+    }
+
+    // hashes of the siblings along the path of the leaf, from the leaf up
+    fn siblings(&self, root: &[u64; 4], index: u32) -> ([u64; 4], [[u64; 4]; DEPTH]) {
+        let mut siblings = [[0; 4]; DEPTH];
+        let mut node = *root;
+        for level in (0..DEPTH).rev() {
+            let (left, right) = self.children(&node, level + 1);
+            if (index >> level) & 1 == 1 {
+                siblings[level] = left;
+                node = right;
+            } else {
+                siblings[level] = right;
+                node = left;
+            }
+        }
+        (node, siblings)
+    }
+}
+
+/// In-memory merkle of depth 32 which computes the same roots as the host merkle, so that
+/// off-chain indexers and sequencers can run `KeyValueMap`, `KeyValueMapU64` and
+/// `CompactKeyValueMap` on the same state as the guest and predict the roots of a proof.
+///
+/// Like the host merkle db, all native merkles of a thread share one node store, so a
+/// native merkle is just a root and `load` accepts any root computed in the thread.
+/// Preimages written with `set` and root hints are kept in that store instead of the data
+/// cache.
+pub struct NativeMerkle {
+    pub root: [u64; 4],
+}
+
+impl Default for NativeMerkle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeMerkle {
+    pub fn load(root: [u64; 4]) -> Self {
+        NativeMerkle { root }
+    }
+
+    /// Empty merkle, whose root is the root of `Merkle::new`
+    pub fn new() -> Self {
+        NativeMerkle {
+            root: default_hashes()[DEPTH],
+        }
+    }
+
+    /// Get the raw leaf data
+    pub fn get_simple(&self, index: u32, data: &mut [u64; 4]) {
+        DB.with(|db| {
+            let db = db.borrow();
+            let (leaf, _) = db.siblings(&self.root, index);
+            *data = db.leaf(&leaf);
+        })
+    }
+
+    /// Set the raw leaf data, failing if the current leaf does not match the hint
+    pub fn set_simple(&mut self, index: u32, data: &[u64; 4], hint: Option<&[u64; 4]>) {
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let (leaf, siblings) = db.siblings(&self.root, index);
+            if let Some(hint) = hint {
+                unsafe { require(db.leaf(&leaf) == *hint) };
+            }
+            let mut node = NativeHasher::hash_leaf(data);
+            db.leaves.insert(node, *data);
+            for (level, sibling) in siblings.iter().enumerate() {
+                let children = if (index >> level) & 1 == 1 {
+                    (*sibling, node)
+                } else {
+                    (node, *sibling)
+                };
+                node = NativeHasher::hash_node(&children.0, &children.1);
+                db.nodes.insert(node, children);
+            }
+            self.root = node;
+        })
+    }
+
+    /// Get the preimage stored with `set` and its hash, the empty leaf has no preimage
+    pub fn get(&self, index: u32, _pad: bool) -> ([u64; 4], Vec<u64>) {
+        let mut hash = [0; 4];
+        self.get_simple(index, &mut hash);
+        if hash == [0; 4] {
+            return (hash, vec![]);
+        }
+        let data = DB.with(|db| db.borrow().data.get(&hash).cloned());
+        unsafe { require(data.is_some()) };
+        (hash, data.unwrap())
+    }
+
+    /// Store the Poseidon hash of data in the leaf and keep data as its preimage
+    pub fn set(&mut self, index: u32, data: &[u64], pad: bool, hint: Option<&[u64; 4]>) {
+        let hash = NativeHasher::hash(data, pad);
+        DB.with(|db| db.borrow_mut().data.insert(hash, data.to_vec()));
+        self.set_simple(index, &hash, hint);
+    }
+}
+
+impl MerkleRoot for NativeMerkle {
+    fn root(&self) -> [u64; 4] {
+        self.root
+    }
+
+    fn restore_root(&mut self, root: [u64; 4]) {
+        self.root = root;
+    }
+}
+
+// there is no get/set pair convention to enforce off-chain
+impl MerkleBackend for NativeMerkle {
+    fn new() -> Self {
+        NativeMerkle::new()
+    }
+    fn load(root: [u64; 4]) -> Self {
+        NativeMerkle::load(root)
+    }
+    fn get_simple(&self, index: u32, data: &mut [u64; 4]) {
+        NativeMerkle::get_simple(self, index, data)
+    }
+    unsafe fn set_simple_unsafe(&mut self, index: u32, data: &[u64; 4]) {
+        NativeMerkle::set_simple(self, index, data, None)
+    }
+    fn set_simple(&mut self, index: u32, data: &[u64; 4], hint: Option<&[u64; 4]>) {
+        NativeMerkle::set_simple(self, index, data, hint)
+    }
+    fn get(&self, index: u32, pad: bool) -> ([u64; 4], Vec<u64>) {
+        NativeMerkle::get(self, index, pad)
+    }
+    fn set(&mut self, index: u32, data: &[u64], pad: bool, hint: Option<&[u64; 4]>) {
+        NativeMerkle::set(self, index, data, pad, hint)
+    }
+    unsafe fn set_unsafe(&mut self, index: u32, data: &[u64], pad: bool) {
+        NativeMerkle::set(self, index, data, pad, None)
+    }
+    fn store_root_hint(root: &[u64; 4], data: &[u64]) {
+        DB.with(|db| db.borrow_mut().hints.insert(*root, data.to_vec()));
+    }
+    fn fetch_root_hint(root: &[u64; 4]) -> Vec<u64> {
+        DB.with(|db| db.borrow().hints.get(root).cloned().unwrap_or_default())
+    }
+}
+
+impl MerklePath for NativeMerkle {
+    fn siblings(&self, index: u32) -> [[u64; 4]; DEPTH] {
+        DB.with(|db| db.borrow().siblings(&self.root, index).1)
+    }
+}
+
+impl_smt_for_backend!(NativeMerkle);
+
+impl SMTWitness for NativeMerkle {
+    fn smt_non_membership(&self, key: &[u64; 4]) -> Option<NonMembershipWitness> {
+        SmtLocal::smt_non_membership_local(self, key)
+    }
+}
@@ -0,0 +1,255 @@
+use crate::kvpair::{KeyValueMap, SMT};
+use crate::require;
+
+/// Key of the record `[first, last, len]` of the list, user keys have a zero second limb
+const HEAD_KEY: [u64; 4] = [0, 1, 0, 0];
+
+const HAS_PREV: u64 = 1;
+const HAS_NEXT: u64 = 2;
+
+struct Node {
+    prev: Option<u64>,
+    next: Option<u64>,
+    value: Vec<u64>,
+}
+
+struct Head {
+    first: Option<u64>,
+    last: Option<u64>,
+    len: u64,
+}
+
+/// Map from u64 keys (prices, expiry times ...) to values, kept as a sorted doubly linked
+/// list in a `KeyValueMap`. The node of key k is stored under `[k, 0, 0, 0]` as
+/// `[prev, next, flags, value ..]`, where the flags tell whether prev and next are set.
+///
+/// Positions in the list come from an untrusted witness: the floor of x, which is the
+/// greatest key less than or equal to x, or None if every key is greater than x. The
+/// floor is checked against the links of the list before it is used, so a wrong witness
+/// fails the proof instead of breaking the order. `find_floor` computes the witness by
+/// walking the list, e.g. off-chain on a `native::NativeMerkle`.
+pub struct OrderedMap<S: SMT> {
+    pub map: KeyValueMap<S>,
+}
+
+impl<S: SMT> OrderedMap<S> {
+    pub fn new(root_merkle: S) -> Self {
+        OrderedMap {
+            map: KeyValueMap::new(root_merkle),
+        }
+    }
+
+    fn head(&self) -> Head {
+        let data = self.map.get(&HEAD_KEY);
+        if data.is_empty() || data[2] == 0 {
+            Head {
+                first: None,
+                last: None,
+                len: 0,
+            }
+        } else {
+            Head {
+                first: Some(data[0]),
+                last: Some(data[1]),
+                len: data[2],
+            }
+        }
+    }
+
+    fn write_head(&mut self, head: &Head) {
+        if head.len == 0 {
+            // an empty list leaves no record behind
+            self.map.remove(&HEAD_KEY);
+        } else {
+            let data = [head.first.unwrap(), head.last.unwrap(), head.len];
+            self.map.set(&HEAD_KEY, &data);
+        }
+    }
+
+    fn node(&self, key: u64) -> Option<Node> {
+        let data = self.map.get(&[key, 0, 0, 0]);
+        if data.is_empty() {
+            return None;
+        }
+        unsafe { require(data.len() >= 3) };
+        Some(Node {
+            prev: (data[2] & HAS_PREV != 0).then_some(data[0]),
+            next: (data[2] & HAS_NEXT != 0).then_some(data[1]),
+            value: data[3..].to_vec(),
+        })
+    }
+
+    fn write_node(&mut self, key: u64, node: &Node) {
+        let flags = node.prev.map_or(0, |_| HAS_PREV) | node.next.map_or(0, |_| HAS_NEXT);
+        let mut data = vec![node.prev.unwrap_or(0), node.next.unwrap_or(0), flags];
+        data.extend_from_slice(&node.value);
+        self.map.set(&[key, 0, 0, 0], &data);
+    }
+
+    // check that floor is the floor of x and return its node
+    fn check_floor(&self, x: u64, floor: Option<u64>) -> Option<Node> {
+        match floor {
+            Some(floor) => {
+                let node = self.node(floor);
+                unsafe {
+                    require(node.is_some());
+                    require(floor <= x);
+                    require(node.as_ref().unwrap().next.map_or(true, |next| next > x));
+                }
+                node
+            }
+            None => {
+                unsafe { require(self.head().first.map_or(true, |first| first > x)) };
+                None
+            }
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.head().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: u64) -> Option<Vec<u64>> {
+        self.node(key).map(|node| node.value)
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.node(key).is_some()
+    }
+
+    /// Smallest key and its value
+    pub fn first(&self) -> Option<(u64, Vec<u64>)> {
+        let first = self.head().first?;
+        Some((first, self.node(first).unwrap().value))
+    }
+
+    /// Greatest key and its value
+    pub fn last(&self) -> Option<(u64, Vec<u64>)> {
+        let last = self.head().last?;
+        Some((last, self.node(last).unwrap().value))
+    }
+
+    /// Insert or update the key given the floor of the key, returns true if the key is new
+    pub fn insert(&mut self, key: u64, value: &[u64], floor: Option<u64>) -> bool {
+        let floor_node = self.check_floor(key, floor);
+        if floor == Some(key) {
+            let mut node = floor_node.unwrap();
+            node.value = value.to_vec();
+            self.write_node(key, &node);
+            return false;
+        }
+        let mut head = self.head();
+        let next = match (floor, floor_node) {
+            (Some(prev), Some(mut prev_node)) => {
+                let next = prev_node.next;
+                prev_node.next = Some(key);
+                self.write_node(prev, &prev_node);
+                next
+            }
+            _ => head.first.replace(key),
+        };
+        match next {
+            Some(next) => {
+                let mut next_node = self.node(next).unwrap();
+                next_node.prev = Some(key);
+                self.write_node(next, &next_node);
+            }
+            None => head.last = Some(key),
+        }
+        let node = Node {
+            prev: floor,
+            next,
+            value: value.to_vec(),
+        };
+        self.write_node(key, &node);
+        head.len += 1;
+        self.write_head(&head);
+        true
+    }
+
+    /// Remove the key and return whether it was present, no witness is needed
+    pub fn remove(&mut self, key: u64) -> bool {
+        let node = match self.node(key) {
+            Some(node) => node,
+            None => return false,
+        };
+        let mut head = self.head();
+        match node.prev {
+            Some(prev) => {
+                let mut prev_node = self.node(prev).unwrap();
+                prev_node.next = node.next;
+                self.write_node(prev, &prev_node);
+            }
+            None => head.first = node.next,
+        }
+        match node.next {
+            Some(next) => {
+                let mut next_node = self.node(next).unwrap();
+                next_node.prev = node.prev;
+                self.write_node(next, &next_node);
+            }
+            None => head.last = node.prev,
+        }
+        self.map.remove(&[key, 0, 0, 0]);
+        head.len -= 1;
+        self.write_head(&head);
+        true
+    }
+
+    /// Greatest key strictly less than x, given the floor of x
+    pub fn predecessor(&self, x: u64, floor: Option<u64>) -> Option<u64> {
+        let floor_node = self.check_floor(x, floor);
+        if floor == Some(x) {
+            floor_node.unwrap().prev
+        } else {
+            floor
+        }
+    }
+
+    /// Smallest key strictly greater than x, given the floor of x
+    pub fn successor(&self, x: u64, floor: Option<u64>) -> Option<u64> {
+        match self.check_floor(x, floor) {
+            Some(node) => node.next,
+            None => self.head().first,
+        }
+    }
+
+    /// Keys in `lo..=hi` with their values in increasing order, given the floor of lo
+    pub fn range(&self, lo: u64, hi: u64, floor: Option<u64>) -> Vec<(u64, Vec<u64>)> {
+        let floor_node = self.check_floor(lo, floor);
+        let mut cursor = match floor_node {
+            Some(_) if floor == Some(lo) => Some(lo),
+            Some(node) => node.next,
+            None => self.head().first,
+        };
+        let mut entries = vec![];
+        while let Some(key) = cursor {
+            if key > hi {
+                break;
+            }
+            let node = self.node(key).unwrap();
+            cursor = node.next;
+            entries.push((key, node.value));
+        }
+        entries
+    }
+
+    /// Floor of x found by walking the list from the first key, which costs one read per
+    /// key not greater than x. Meant to compute the witness of the other calls off-chain.
+    pub fn find_floor(&self, x: u64) -> Option<u64> {
+        let mut floor = None;
+        let mut cursor = self.head().first;
+        while let Some(key) = cursor {
+            if key > x {
+                break;
+            }
+            floor = Some(key);
+            cursor = self.node(key).unwrap().next;
+        }
+        floor
+    }
+}
@@ -5,14 +5,35 @@ extern "C" {
     pub fn wasm_trace_size() -> u64;
 }
 
+use crate::append_only::AppendOnlyMerkle;
+use crate::bitmap::{MerkleBitmap, SparseBitmap};
+use crate::checkpoint::Checkpoints;
+use crate::codec::{KeyCodec, LeafCodec};
+use crate::forest::MerkleForest;
+use crate::history::RootHistory;
 use crate::jubjub::BabyJubjubPoint;
 use crate::jubjub::JubjubSignature;
+use crate::kvpair::BytesKeyValueMap;
+use crate::kvpair::CompactKeyValueMap;
 use crate::kvpair::KeyValueMap;
 use crate::kvpair::KeyValueMapU64;
-use crate::merkle::Merkle;
+#[cfg(feature = "native")]
+use crate::kvpair::NonMembershipWitness;
+use crate::kvpair::TypedKeyValueMap;
+use crate::kvpair::SMT;
+use crate::kvpair::{namespace_list, register_namespace, Namespace};
+use crate::merkle::{Merkle, MerkleBackend, MerkleError, RecordLayout, TypedMerkle};
+use crate::merkle_queue::MerkleQueue;
+use crate::merkle_vec::MerkleVec;
+use crate::mmr::{MerkleMountainRange, MmrProof};
+#[cfg(feature = "native")]
+use crate::native::{NativeHasher, NativeMerkle};
+use crate::ordered_map::OrderedMap;
 use primitive_types::U256;
+use std::cell::RefCell;
 
 use crate::poseidon::PoseidonHasher;
+use derive_builder::{KeyCodec, LeafCodec};
 use wasm_bindgen::prelude::*;
 
 pub fn test_merkle() {
@@ -54,6 +75,66 @@ pub fn test_merkle() {
     unsafe {
         require(leaf2 == [4, 5, 6, 7]);
     }
+
+    crate::dbg!("testing merkle range, index: 2..5\n");
+    merkle.set_range(2, &[[1, 0, 0, 0], [2, 0, 0, 0], [3, 0, 0, 0]]);
+    let leaves = merkle.get_range(1, 5);
+    unsafe {
+        require(leaves[0] == [4, 5, 6, 7]);
+        require(leaves[1..4] == [[1, 0, 0, 0], [2, 0, 0, 0], [3, 0, 0, 0]]);
+        require(leaves[4] == [0, 0, 0, 0]);
+    }
+
+    crate::dbg!("testing merkle record, index: 2\n");
+    let layout = RecordLayout::new(6);
+    let mut records = Merkle::new();
+    records.set_record(&layout, 2, &[1, 2, 3, 4, 5, 6]);
+    let mut second_leaf = [0; 4];
+    records.get_simple(5, &mut second_leaf);
+    unsafe {
+        require(layout.leaves() == 2);
+        require(records.get_record(&layout, 2) == [1, 2, 3, 4, 5, 6]);
+        require(records.get_record(&layout, 1) == [0; 6]);
+        require(second_leaf == [5, 6, 0, 0]);
+    }
+
+    crate::dbg!("testing merkle remove, index: 0\n");
+    let mut fresh = Merkle::new();
+    fresh.set(0, &[9], true, None);
+    let hash = PoseidonHasher::hash(&[9], true);
+    fresh.remove(0, Some(&hash));
+    let (removed, content) = fresh.get(0, true);
+    unsafe {
+        require(removed == [0, 0, 0, 0]);
+        require(content.is_empty());
+        require(fresh.root == Merkle::new().root);
+    }
+}
+
+pub fn test_merkle_errors() {
+    let mut merkle = Merkle::new();
+    merkle.set(0, &[1, 2, 3, 4, 5], true, None);
+    // a raw leaf which has no preimage in the cache
+    merkle.set_simple(1, &[1, 2, 3, 4], None);
+    let root = merkle.root;
+
+    let mut buf = [0; 2];
+    let r = merkle.try_set(0, &[6], true, Some(&[0; 4]));
+    unsafe {
+        require(merkle.try_get(2, true) == Ok(([0; 4], vec![])));
+        require(merkle.try_get(0, true).unwrap().1 == [1, 2, 3, 4, 5]);
+        require(merkle.try_get(1, true) == Err(MerkleError::CacheMiss));
+        require(merkle.try_get_into(0, true, &mut buf) == Err(MerkleError::BufferTooSmall));
+        require(r == Err(MerkleError::HashMismatch));
+        require(merkle.root == root);
+    }
+
+    let mut log = AppendOnlyMerkle::new();
+    log.append(&[1]);
+    unsafe {
+        require(log.try_get(0) == Ok(vec![1]));
+        require(log.try_get(1) == Err(MerkleError::IndexOutOfRange));
+    }
 }
 
 fn test_kvpair_value(kvpair: &mut KeyValueMap<Merkle>, key: &[u64; 4], data: &[u64]) {
@@ -125,6 +206,660 @@ pub fn test_kvpair() {
     unsafe { require(content.len() == 0) };
 }
 
+pub fn test_kvpair_remove() {
+    let key1 = [1, 2, 3, 4];
+    let key2 = [1, 5, 3, 4];
+    let key3 = [(1u64 << 32) + 1, 5, 3, 4];
+    let key4 = [1, 5, 3, 5];
+
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    kvpair.set(&key1, &[1]);
+    kvpair.set(&key2, &[2, 3]);
+    kvpair.set(&key3, &[4, 5, 6]);
+    kvpair.set(&key4, &[7]);
+    unsafe {
+        require(kvpair.remove(&key2));
+        require(!kvpair.remove(&key2));
+        require(kvpair.remove(&key3));
+    }
+    test_kvpair_value(&mut kvpair, &key1, &[1]);
+    test_kvpair_value(&mut kvpair, &key2, &[]);
+    test_kvpair_value(&mut kvpair, &key4, &[7]);
+
+    // the same content inserted in another order gives the same root
+    let mut expected = KeyValueMap::new(Merkle::new());
+    expected.set(&key4, &[7]);
+    expected.set(&key1, &[1]);
+    unsafe { require(kvpair.merkle.root == expected.merkle.root) };
+
+    kvpair.remove(&key1);
+    kvpair.remove(&key4);
+    unsafe { require(kvpair.merkle.root == Merkle::new().root) };
+}
+
+// a map holding key1 and key2 under a tree node whose sub merkle has no key count hint, as
+// written before the hint existed. Both keys share the low 32 bits of their first limb.
+fn legacy_kvpair<M: MerkleBackend + SMT>(key1: &[u64; 4], key2: &[u64; 4]) -> KeyValueMap<M> {
+    let mut sub_merkle = M::new();
+    for (key, value) in [(key1, 10), (key2, 20)] {
+        let leaf = [0, key[0], key[1], key[2], key[3], value];
+        sub_merkle.set((key[0] >> 32) as u32, &leaf, true, None);
+    }
+    let root = sub_merkle.root();
+    let mut merkle = M::new();
+    merkle.set(
+        key1[0] as u32,
+        &[1, root[0], root[1], root[2], root[3]],
+        true,
+        None,
+    );
+    KeyValueMap::new(merkle)
+}
+
+fn is_tree_node<M: MerkleBackend + SMT>(kvpair: &KeyValueMap<M>, index: u32) -> bool {
+    let (_, node) = kvpair.merkle.get(index, true);
+    node.len() == 5 && node[0] == 1
+}
+
+pub fn test_kvpair_legacy_node() {
+    let key1 = [(1u64 << 32) + 1, 5, 0, 0];
+    let key2 = [(2u64 << 32) + 1, 5, 0, 0];
+    let key3 = [(3u64 << 32) + 1, 5, 0, 0];
+    // shares the first two levels with key1
+    let key4 = [(1u64 << 32) + 1, 6, 0, 0];
+
+    let mut kvpair = legacy_kvpair::<Merkle>(&key1, &key2);
+    test_kvpair_value(&mut kvpair, &key1, &[10]);
+    test_kvpair_value(&mut kvpair, &key2, &[20]);
+    // the node format is unchanged, only the hint is missing
+    let mut expected = KeyValueMap::new(Merkle::new());
+    expected.set(&key1, &[10]);
+    expected.set(&key2, &[20]);
+    unsafe { require(expected.merkle.root == kvpair.merkle.root) };
+
+    kvpair.set(&key3, &[30]);
+    kvpair.set(&key1, &[11]);
+    kvpair.set(&key4, &[40]);
+    unsafe { require(is_tree_node(&kvpair, 1)) };
+    test_kvpair_value(&mut kvpair, &key1, &[11]);
+    test_kvpair_value(&mut kvpair, &key3, &[30]);
+    test_kvpair_value(&mut kvpair, &key4, &[40]);
+
+    // the hinted sub merkle of key1 and key4 collapses, the one without hint does not
+    unsafe {
+        require(kvpair.remove(&key4));
+        require(kvpair.remove(&key2));
+        require(kvpair.remove(&key3));
+        require(!kvpair.remove(&key3));
+        require(is_tree_node(&kvpair, 1));
+    }
+    test_kvpair_value(&mut kvpair, &key1, &[11]);
+    test_kvpair_value(&mut kvpair, &key2, &[]);
+    test_kvpair_value(&mut kvpair, &key4, &[]);
+    // until its last key is removed
+    unsafe {
+        require(kvpair.remove(&key1));
+        require(kvpair.merkle.root == Merkle::new().root);
+    }
+
+    // a wrong hint does not collapse a sub merkle which holds more than one key
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    for (key, value) in [(key1, 10), (key2, 20), (key3, 30)] {
+        kvpair.set(&key, &[value]);
+    }
+    let (_, node) = kvpair.merkle.get(1, true);
+    let sub_root: [u64; 4] = node[1..5].try_into().unwrap();
+    // claims that only key1 and key3 are below the node
+    Merkle::store_root_hint(&sub_root, &[2, key1[0] + key3[0], 10, 0, 0]);
+    unsafe {
+        require(kvpair.remove(&key3));
+        require(is_tree_node(&kvpair, 1));
+    }
+    test_kvpair_value(&mut kvpair, &key1, &[10]);
+    test_kvpair_value(&mut kvpair, &key2, &[20]);
+}
+
+#[cfg(feature = "native")]
+pub fn test_kvpair_non_membership() {
+    let key1 = [1, 2, 3, 4];
+    let key2 = [1, 5, 3, 4];
+    let absent_empty = [2, 5, 3, 4];
+    let absent_leaf = [1, 5, 3, 6];
+
+    // witnesses are built where the merkle paths are known, on the native copy of the map
+    let mut kvpair = KeyValueMap::new(NativeMerkle::new());
+    let mut host = KeyValueMap::new(Merkle::new());
+    kvpair.set(&key1, &[1]);
+    kvpair.set(&key2, &[2, 3]);
+    host.set(&key1, &[1]);
+    host.set(&key2, &[2, 3]);
+    let root = host.merkle.root;
+
+    let empty_witness = kvpair.non_membership(&absent_empty).unwrap();
+    let leaf_witness = kvpair.non_membership(&absent_leaf).unwrap();
+    let forged = NonMembershipWitness {
+        terminal: vec![],
+        ..leaf_witness.clone()
+    };
+    let mut wrong_sibling = leaf_witness.clone();
+    wrong_sibling.siblings[0][5] = [1, 0, 0, 0];
+    unsafe {
+        require(kvpair.merkle.root == root);
+        require(kvpair.non_membership(&key2).is_none());
+        require(empty_witness.nodes.is_empty() && empty_witness.terminal.is_empty());
+        require(empty_witness.verify(&root, &absent_empty));
+        require(leaf_witness.nodes.len() == 2 && leaf_witness.siblings.len() == 3);
+        require(leaf_witness.verify(&root, &absent_leaf));
+        // the witness of an absent key does not prove the absence of a present key
+        require(!leaf_witness.verify(&root, &key2));
+        require(!forged.verify(&root, &absent_leaf));
+        require(!wrong_sibling.verify(&root, &absent_leaf));
+        require(!leaf_witness.verify(&Merkle::new().root, &absent_leaf));
+    }
+
+    // a path through a sub merkle without hint
+    let legacy1 = [(1u64 << 32) + 1, 5, 0, 0];
+    let legacy2 = [(2u64 << 32) + 1, 5, 0, 0];
+    let absent = [(3u64 << 32) + 1, 5, 0, 0];
+    let legacy = legacy_kvpair::<NativeMerkle>(&legacy1, &legacy2);
+    let witness = legacy.non_membership(&absent).unwrap();
+    unsafe {
+        require(witness.nodes[0].len() == 5);
+        require(witness.verify(&legacy.merkle.root, &absent));
+        require(legacy.non_membership(&legacy1).is_none());
+    }
+}
+
+#[derive(LeafCodec, KeyCodec, PartialEq, Clone, Debug)]
+struct Position {
+    owner: u64,
+    market: u32,
+}
+
+pub fn test_typed_kvpair() {
+    let mut balances = TypedKeyValueMap::<U256, (u64, u32), _>::new(Merkle::new());
+    let alice = U256([1, 2, 3, 4]);
+    balances.set(&alice, &(100, 1));
+    unsafe {
+        require(balances.get(&alice) == Some((100, 1)));
+        require(balances.get(&U256([1, 2, 3, 5])).is_none());
+    }
+
+    let mut history = TypedKeyValueMap::<str, Vec<u64>, _>::new(Merkle::new());
+    history.set("alice", &vec![]);
+    unsafe {
+        // an empty vector is told apart from a missing key
+        require(history.get("alice") == Some(vec![]));
+        require(history.get("bob").is_none());
+        require(history.contains("alice"));
+        require(history.remove("alice"));
+        require(!history.contains("alice"));
+    }
+
+    let mut positions = TypedKeyValueMap::<Position, Account, _>::new(Merkle::new());
+    let position = Position {
+        owner: 1,
+        market: 2,
+    };
+    let account = Account {
+        balance: 100,
+        nonce: 1,
+        pk: BabyJubjubPoint {
+            x: U256([0, 0, 0, 0]),
+            y: U256([1, 0, 0, 0]),
+        },
+        history: vec![1, 2],
+    };
+    positions.set(&position, &account);
+    unsafe {
+        require(position.to_key() == position.to_limbs().to_key());
+        require(positions.get(&position) == Some(account));
+    }
+}
+
+pub fn test_kvpair_entry() {
+    let key1 = [1, 2, 3, 4];
+    let key2 = [1, 5, 3, 4];
+    let key3 = [2, 5, 3, 4];
+
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    kvpair.set(&key1, &[1]);
+    kvpair.set(&key2, &[2, 3]);
+
+    let mut expected = KeyValueMap::new(Merkle::new());
+    expected.set(&key1, &[1]);
+    expected.set(&key2, &[2, 4]);
+    expected.set(&key3, &[5]);
+
+    // update of a key in a sub merkle
+    kvpair.entry(&key2).and_modify(|v| v[1] += 1);
+    // insert of a new key, and_modify is skipped
+    let v = kvpair
+        .entry(&key3)
+        .and_modify(|v| v[0] += 1)
+        .or_insert(&[5]);
+    unsafe {
+        require(v == [5]);
+        require(kvpair.entry(&key1).get() == Some(&[1][..]));
+        require(kvpair.merkle.root == expected.merkle.root);
+    }
+
+    kvpair.entry(&key1).update(|v| {
+        let old = v.unwrap()[0];
+        vec![old + 10, old]
+    });
+    expected.set(&key1, &[11, 1]);
+    test_kvpair_value(&mut kvpair, &key1, &[11, 1]);
+    unsafe { require(kvpair.merkle.root == expected.merkle.root) };
+
+    // entries below a sub merkle without hint write the same nodes as set
+    let legacy1 = [(1u64 << 32) + 1, 5, 0, 0];
+    let legacy2 = [(2u64 << 32) + 1, 5, 0, 0];
+    let legacy3 = [(3u64 << 32) + 1, 5, 0, 0];
+    let mut kvpair = legacy_kvpair::<Merkle>(&legacy1, &legacy2);
+    let mut expected = legacy_kvpair::<Merkle>(&legacy1, &legacy2);
+    kvpair.entry(&legacy1).and_modify(|v| v[0] += 1);
+    kvpair.entry(&legacy3).or_insert(&[30]);
+    expected.set(&legacy1, &[11]);
+    expected.set(&legacy3, &[30]);
+    unsafe {
+        require(is_tree_node(&kvpair, 1));
+        require(kvpair.merkle.root == expected.merkle.root);
+    }
+}
+
+pub fn test_bytes_kvpair() {
+    let mut kvpair = BytesKeyValueMap::new(Merkle::new());
+    let long_key = b"0x5b38da6a701c568545dcfcb03fcb875f56beddc4/token/1";
+    kvpair.set(b"alice", &[1, 2]);
+    kvpair.set(long_key, &[]);
+    kvpair.set(b"alice", &[3]);
+    unsafe {
+        require(kvpair.get(b"alice") == Some(vec![3]));
+        require(kvpair.get(long_key) == Some(vec![]));
+        require(kvpair.get(b"alice\0").is_none());
+        require(kvpair.get(b"").is_none());
+        require(kvpair.remove(b"alice"));
+        require(!kvpair.contains(b"alice"));
+    }
+}
+
+pub fn test_compact_kvpair() {
+    // the u64 instance keeps the leaf encoding of KeyValueMapU64
+    let mut u64_map = KeyValueMapU64::new(Merkle::new());
+    let mut compact = CompactKeyValueMap::<u64, _>::new(Merkle::new());
+    let mut raw = Merkle::new();
+    u64_map.set_pair(5, &[7, 8]);
+    compact.set(&5, &[7, 8]);
+    raw.set_simple(5, &[5, 7, 8, 1 << 61], None);
+    unsafe {
+        require(u64_map.merkle.root == raw.root);
+        require(compact.merkle.root == raw.root);
+    }
+
+    // (account, token) keys which share their lowest 96 bits go four levels deep
+    let mut pairs = CompactKeyValueMap::<[u64; 2], _>::new(Merkle::new());
+    let mut wide = CompactKeyValueMap::<u128, _>::new(Merkle::new());
+    let keys = [[3, 1], [3, 2], [3, 1 << 32], [4, 1]];
+    for (i, key) in keys.iter().enumerate() {
+        pairs.set(key, &[i as u64 + 10]);
+        wide.set(
+            &(key[0] as u128 | ((key[1] as u128) << 64)),
+            &[i as u64 + 10],
+        );
+    }
+    unsafe {
+        require(pairs.merkle.root == wide.merkle.root);
+        for (i, key) in keys.iter().enumerate() {
+            require(pairs.get(key) == Some(vec![i as u64 + 10]));
+        }
+        require(pairs.get(&[3, 3]).is_none());
+        require(pairs.remove(&[3, 2]));
+        require(!pairs.contains(&[3, 2]));
+        require(pairs.get(&[3, 1 << 32]) == Some(vec![12]));
+    }
+}
+
+pub fn test_namespace() {
+    let mut merkle = Merkle::new();
+    unsafe {
+        require(register_namespace(&mut merkle, 1));
+        require(register_namespace(&mut merkle, 2));
+        require(!register_namespace(&mut merkle, 1));
+    }
+    let registered = merkle.root;
+
+    // the namespaces share the merkle at the same time
+    let shared = RefCell::new(merkle);
+    let mut balances = TypedKeyValueMap::<u64, u64, _>::new(Namespace::new(&shared, 1));
+    let mut allowances = TypedKeyValueMap::<u64, u64, _>::new(Namespace::new(&shared, 2));
+    balances.set(&7, &100);
+    unsafe { require(allowances.get(&7).is_none()) };
+    allowances.set(&7, &5);
+    unsafe { require(balances.get(&7) == Some(100)) };
+
+    // opening a namespace, even an unregistered one, writes nothing
+    let metadata = TypedKeyValueMap::<u64, u64, _>::new(Namespace::new(&shared, 3));
+    let root = shared.borrow().root;
+    unsafe {
+        require(metadata.get(&7).is_none());
+        require(shared.borrow().root == root && root != registered);
+    }
+    let raw = KeyValueMap::new(Merkle::load(root));
+    unsafe {
+        require(raw.get(&[7, 0, 0, 0]).is_empty());
+        require(namespace_list(&raw.merkle) == vec![1, 2]);
+    }
+}
+
+#[cfg(feature = "native")]
+pub fn test_native() {
+    // the native hashes and roots match the host ones
+    let data: Vec<u64> = (1..40).collect();
+    for len in [1, 3, 8, 24, 39] {
+        for pad in [true, false] {
+            unsafe {
+                require(
+                    NativeHasher::hash(&data[0..len], pad)
+                        == PoseidonHasher::hash(&data[0..len], pad),
+                )
+            };
+        }
+    }
+    // hash outputs use the whole field element, unlike the small limbs above
+    let left = PoseidonHasher::hash(&[1, 2, 3], true);
+    let right = PoseidonHasher::hash(&[4, 5, 6], true);
+    let node = [left, right].concat();
+    unsafe {
+        require(NativeHasher::hash(&node, false) == PoseidonHasher::hash(&node, false));
+        require(
+            NativeHasher::hash(&[&[1], node.as_slice()].concat(), true)
+                == MerkleMountainRange::node_hash(&left, &right),
+        );
+    }
+
+    let mut merkle = Merkle::new();
+    let mut native = NativeMerkle::new();
+    unsafe { require(native.root == merkle.root) };
+    merkle.set_simple(3, &[1, 2, 3, 4], None);
+    native.set_simple(3, &[1, 2, 3, 4], None);
+    merkle.set(u32::MAX, &[5, 6, 7], true, None);
+    native.set(u32::MAX, &[5, 6, 7], true, None);
+    unsafe { require(native.root == merkle.root) };
+
+    // the keys of test_kvpair share path segments down to the last level, so they build
+    // tree nodes at every level which the removals collapse again
+    let keys = [
+        [1, 2, 3, 4],
+        [1, 5, 3, 4],
+        [(1u64 << 32) + 1, 5, 3, 4],
+        [1, 5, 3, 5],
+        [1, 5, 3, (2u64 << 32) + 5],
+        [1, 5, 4, (2u64 << 32) + 5],
+    ];
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    let mut native_kvpair = KeyValueMap::new(NativeMerkle::new());
+    for (i, key) in keys.iter().enumerate() {
+        kvpair.set(key, &[i as u64, 7]);
+        native_kvpair.set(key, &[i as u64, 7]);
+        unsafe { require(native_kvpair.merkle.root == kvpair.merkle.root) };
+    }
+    for key in [keys[2], keys[4], keys[1]] {
+        unsafe {
+            require(kvpair.remove(&key) && native_kvpair.remove(&key));
+            require(native_kvpair.merkle.root == kvpair.merkle.root);
+        }
+    }
+    let mut expected = KeyValueMap::new(NativeMerkle::new());
+    for i in [5, 3, 0] {
+        expected.set(&keys[i], &[i as u64, 7]);
+    }
+    unsafe {
+        require(expected.merkle.root == kvpair.merkle.root);
+        require(native_kvpair.get(&keys[3]) == vec![3, 7]);
+    }
+    // entries share the path writes of set on both backends
+    kvpair.entry(&keys[3]).and_modify(|v| v[1] += 1);
+    native_kvpair.entry(&keys[3]).and_modify(|v| v[1] += 1);
+    kvpair.entry(&keys[2]).or_insert(&[2, 7]);
+    native_kvpair.entry(&keys[2]).or_insert(&[2, 7]);
+    expected.set(&keys[3], &[3, 8]);
+    expected.set(&keys[2], &[2, 7]);
+    unsafe {
+        require(native_kvpair.merkle.root == kvpair.merkle.root);
+        require(expected.merkle.root == kvpair.merkle.root);
+    }
+
+    // 1 and 1 + (1 << 32) share the first level, which makes an IS_NODE_BIT node
+    let mut kvpair_u64 = KeyValueMapU64::new(Merkle::new());
+    let mut native_u64 = KeyValueMapU64::new(NativeMerkle::new());
+    for key in [1, 1 + (1 << 32), 2] {
+        kvpair_u64.set(key, key);
+        native_u64.set(key, key);
+    }
+    unsafe {
+        require(native_u64.merkle.root == kvpair_u64.merkle.root);
+        require(native_u64.get(1 + (1 << 32)) == Some(1 + (1 << 32)));
+    }
+    // the emptied sub merkle goes back to an empty leaf
+    for key in [1 + (1 << 32), 1] {
+        unsafe {
+            require(kvpair_u64.remove(key) && native_u64.remove(key));
+            require(native_u64.merkle.root == kvpair_u64.merkle.root);
+        }
+    }
+    let mut expected_u64 = KeyValueMapU64::new(NativeMerkle::new());
+    expected_u64.set(2, 2);
+    unsafe { require(expected_u64.merkle.root == kvpair_u64.merkle.root) };
+}
+
+pub fn test_kvpair_batch() {
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    let mut batched = KeyValueMap::new(Merkle::new());
+    kvpair.set(&[1, 0, 0, 0], &[1]);
+    batched.set(&[1, 0, 0, 0], &[1]);
+    // keys sharing the first path segments with each other and with the existing key
+    let keys = [
+        [1, 0, 0, 0],
+        [1 + (1 << 32), 0, 0, 0],
+        [1 + (2 << 32), 0, 0, 0],
+        [1 + (2 << 32), 1, 0, 0],
+        [2, 0, 0, 0],
+        [1 + (1 << 32), 0, 0, 0],
+    ];
+    let values: Vec<Vec<u64>> = (0..keys.len() as u64).map(|i| vec![i + 10]).collect();
+    let entries: Vec<([u64; 4], &[u64])> = keys
+        .iter()
+        .zip(values.iter())
+        .map(|(key, value)| (*key, value.as_slice()))
+        .collect();
+    for (key, value) in entries.iter() {
+        kvpair.set(key, value);
+    }
+    batched.set_many(&entries);
+    unsafe {
+        require(batched.merkle.root == kvpair.merkle.root);
+        require(
+            batched.get_many(&[[1 + (1 << 32), 0, 0, 0], [3, 0, 0, 0]]) == vec![vec![15], vec![]],
+        );
+        // the counts of the tree nodes allow the collapses of later removes
+        require(batched.remove(&[1 + (2 << 32), 1, 0, 0]));
+        require(kvpair.remove(&[1 + (2 << 32), 1, 0, 0]));
+        require(batched.merkle.root == kvpair.merkle.root);
+    }
+
+    // a batch below a sub merkle without hint writes the same nodes as set
+    let legacy1 = [(1u64 << 32) + 1, 5, 0, 0];
+    let legacy2 = [(2u64 << 32) + 1, 5, 0, 0];
+    let mut kvpair = legacy_kvpair::<Merkle>(&legacy1, &legacy2);
+    let mut batched = legacy_kvpair::<Merkle>(&legacy1, &legacy2);
+    let entries: Vec<([u64; 4], &[u64])> = vec![
+        (legacy1, &[11]),
+        ([(3u64 << 32) + 1, 5, 0, 0], &[12]),
+        ([(3u64 << 32) + 1, 6, 0, 0], &[13]),
+        ([2, 0, 0, 0], &[14]),
+        (legacy1, &[15]),
+    ];
+    for (key, value) in entries.iter() {
+        kvpair.set(key, value);
+    }
+    batched.set_many(&entries);
+    unsafe {
+        require(batched.merkle.root == kvpair.merkle.root);
+        require(is_tree_node(&batched, 1));
+        require(batched.get(&legacy1) == vec![15]);
+        require(batched.get(&legacy2) == vec![20]);
+    }
+}
+
+pub fn test_merkle_vec() {
+    let mut orders = MerkleVec::<(u64, u32)>::new();
+    unsafe { require(orders.pop().is_none()) };
+    for i in 0..4 {
+        unsafe { require(orders.push(&(i * 100, i as u32)) == i as u32) };
+    }
+    orders.set(1, &(150, 1));
+    unsafe {
+        require(orders.len() == 4);
+        require(orders.get(1) == Some((150, 1)));
+        require(orders.get(4).is_none());
+        require(orders.swap_remove(0) == (0, 0));
+        require(orders.get(0) == Some((300, 3)));
+        require(orders.pop() == Some((200, 2)));
+        require(orders.len() == 2);
+    }
+
+    // nested in the value of a KeyValueMap through its root
+    let mut kvpair = KeyValueMap::new(Merkle::new());
+    kvpair.set(&[7, 0, 0, 0], &orders.root());
+    let root: [u64; 4] = kvpair.get(&[7, 0, 0, 0]).try_into().unwrap();
+    let orders = MerkleVec::<(u64, u32)>::load(root);
+    unsafe { require(orders.get(1) == Some((150, 1))) };
+
+    // popping back to empty restores the empty root
+    let mut players = MerkleVec::<u64>::new();
+    players.push(&1);
+    players.swap_remove(0);
+    unsafe { require(players.root() == Merkle::new().root) };
+}
+
+pub fn test_merkle_queue() {
+    let mut deposits = MerkleQueue::<(u64, u64)>::new();
+    unsafe { require(deposits.dequeue().is_none()) };
+    deposits.enqueue(&(1, 100));
+    deposits.enqueue(&(2, 200));
+    unsafe {
+        require(deposits.len() == 2);
+        require(deposits.peek() == Some((1, 100)));
+        require(deposits.dequeue() == Some((1, 100)));
+    }
+    // a later proof processes the rest of the queue
+    let mut deposits = MerkleQueue::<(u64, u64)>::load(deposits.root());
+    deposits.enqueue(&(3, 300));
+    unsafe {
+        require(deposits.dequeue() == Some((2, 200)));
+        require(deposits.dequeue() == Some((3, 300)));
+        require(deposits.is_empty());
+    }
+
+    // the slots wrap around once the counters pass the last slot
+    let mut merkle = Merkle::new();
+    let last = u32::MAX as u64 - 1;
+    merkle.set_simple(u32::MAX, &[last, last, 0, 0], None);
+    let mut queue = MerkleQueue::<u64>::load(merkle.root);
+    queue.enqueue(&7);
+    queue.enqueue(&8);
+    let mut leaf = [0; 4];
+    queue.merkle.get_simple(0, &mut leaf);
+    unsafe {
+        require(leaf != [0; 4]);
+        require(queue.dequeue() == Some(7));
+        require(queue.dequeue() == Some(8));
+    }
+}
+
+pub fn test_bitmap() {
+    let mut claimed = MerkleBitmap::new();
+    claimed.test_and_set(3);
+    claimed.test_and_set(200);
+    // a second claim is reported by try_set instead of failing the proof
+    unsafe { require(!claimed.try_set(3) && claimed.try_set(4)) };
+    claimed.set(3);
+    claimed.set(1 << 30);
+    let mut leaf = [0; 4];
+    claimed.merkle.get_simple(0, &mut leaf);
+    unsafe {
+        require(claimed.test(3) && claimed.test(200) && claimed.test(1 << 30));
+        require(!claimed.test(5) && !claimed.test(256 + 3));
+        // flags 3, 4 and 200 share the first leaf
+        require(leaf == [1 << 3 | 1 << 4, 0, 0, 1 << 8]);
+    }
+
+    let mut nullifiers = SparseBitmap::<[u64; 4], _>::new(Merkle::new());
+    let nullifier = [0x1234, 5, 6, 7];
+    nullifiers.test_and_set(&nullifier);
+    nullifiers.test_and_set(&[0x1235, 5, 6, 7]);
+    unsafe {
+        require(!nullifiers.try_set(&nullifier));
+        require(nullifiers.test(&nullifier));
+        require(!nullifiers.test(&[0x1236, 5, 6, 7]));
+        require(!nullifiers.test(&[0x1234, 5, 6, 8]));
+        // members are stored under the hash of their key
+        require(nullifiers.map.get(&nullifier).is_empty());
+        require(
+            nullifiers
+                .map
+                .get(&SparseBitmap::<[u64; 4], Merkle>::member_key(&nullifier))
+                == vec![1],
+        );
+    }
+    nullifiers.set(&nullifier);
+
+    let mut spent = SparseBitmap::<str, _>::new(Merkle::new());
+    spent.test_and_set("note-1");
+    unsafe {
+        require(spent.test("note-1") && !spent.test("note-2"));
+        require(spent.try_set("note-2"));
+    }
+}
+
+pub fn test_ordered_map() {
+    let mut book = OrderedMap::new(Merkle::new());
+    for price in [50, 10, 30, 70] {
+        let floor = book.find_floor(price);
+        unsafe { require(book.insert(price, &[price * 2], floor)) };
+    }
+    // updating a key needs the key itself as floor
+    unsafe { require(!book.insert(30, &[7], Some(30))) };
+    unsafe {
+        require(book.len() == 4);
+        require(book.first() == Some((10, vec![20])));
+        require(book.last() == Some((70, vec![140])));
+        require(book.get(30) == Some(vec![7]));
+        require(book.find_floor(40) == Some(30) && book.find_floor(5).is_none());
+        require(book.successor(40, Some(30)) == Some(50));
+        require(book.successor(70, Some(70)).is_none());
+        require(book.predecessor(30, Some(30)) == Some(10));
+        require(book.predecessor(5, None).is_none());
+        require(book.range(30, 60, Some(30)) == vec![(30, vec![7]), (50, vec![100])]);
+        require(book.range(0, 20, None) == vec![(10, vec![20])]);
+    }
+
+    unsafe {
+        require(book.remove(10) && book.remove(70));
+        require(!book.remove(10));
+        require(book.first() == Some((30, vec![7])));
+        require(book.last() == Some((50, vec![100])));
+        require(book.range(0, u64::MAX, None).len() == 2);
+    }
+    book.remove(30);
+    book.remove(50);
+    unsafe {
+        require(book.is_empty());
+        require(book.map.merkle.root == Merkle::new().root);
+    }
+}
+
 pub fn test_kvpair_u64() {
     let merkle = Merkle::new();
     let mut kvpair = KeyValueMapU64::new(merkle);
@@ -148,12 +883,286 @@ pub fn test_kvpair_u64() {
             let data_in = kvpair.get(key);
             let delta_size = unsafe { wasm_trace_size() - trace_size };
             crate::dbg!("get size is {}\n", delta_size);
-            if data != data_in {
-                crate::dbg!("key {} data {}, data_in {}\n", key, data, data_in);
-            }
-            unsafe { require(data == data_in) };
+            unsafe { require(data_in == Some(data)) };
         }
     }
+
+    // a key holding zero is told apart from a missing key
+    kvpair.set(7 << 32, 0);
+    unsafe {
+        require(kvpair.get(7 << 32) == Some(0));
+        require(kvpair.get(8 << 32).is_none());
+        require(!kvpair.contains(8 << 32));
+    }
+
+    // two limb records
+    kvpair.set_pair(1, &[100, 3]);
+    unsafe {
+        require(kvpair.get_pair(1) == Some([100, 3]));
+        require(kvpair.get(1) == Some(100));
+    }
+
+    // removing a key clears its slot, and a deeper merkle left without keys is
+    // cleared as well
+    let before = kvpair.merkle.root;
+    kvpair.set(5, 1);
+    kvpair.set(5 + (1 << 32), 2);
+    unsafe {
+        require(kvpair.remove(5));
+        require(!kvpair.remove(5));
+        require(kvpair.get(5).is_none());
+        require(kvpair.get(5 + (1 << 32)) == Some(2));
+        require(kvpair.remove(5 + (1 << 32)));
+        require(kvpair.merkle.root == before);
+    }
+}
+
+pub fn test_checkpoint() {
+    let mut merkle = Merkle::new();
+    let mut checkpoints = Checkpoints::new();
+    merkle.set_simple(0, &[1, 0, 0, 0], None);
+    let root = merkle.root;
+
+    checkpoints.checkpoint(&merkle);
+    merkle.set_simple(0, &[2, 0, 0, 0], None);
+    let inner_root = merkle.root;
+
+    // nested checkpoint which is rolled back
+    checkpoints.checkpoint(&merkle);
+    merkle.set_simple(1, &[3, 0, 0, 0], None);
+    let discarded = checkpoints.rollback(&mut merkle);
+    unsafe {
+        require(discarded != inner_root);
+        require(merkle.root == inner_root);
+        require(checkpoints.depth() == 1);
+    }
+
+    // outer checkpoint is rolled back as well
+    checkpoints.rollback(&mut merkle);
+    let mut leaf = [0; 4];
+    merkle.get_simple(0, &mut leaf);
+    unsafe {
+        require(merkle.root == root);
+        require(leaf == [1, 0, 0, 0]);
+    }
+
+    // committed changes are kept
+    let mut kvpair = KeyValueMap::new(merkle);
+    checkpoints.checkpoint(&kvpair);
+    kvpair.set(&[1, 2, 3, 4], &[5]);
+    checkpoints.commit();
+    unsafe {
+        require(checkpoints.depth() == 0);
+        require(kvpair.get(&[1, 2, 3, 4]) == [5]);
+    }
+}
+
+pub fn test_append_only() {
+    let mut log = AppendOnlyMerkle::new();
+    let i0 = log.append(&[1, 2, 3]);
+    let i1 = log.append(&[4]);
+    let old_root = log.merkle.root;
+    log.append_at(2, &[5, 6, 7, 8, 9]);
+    unsafe {
+        require(i0 == 0 && i1 == 1);
+        require(log.len() == 3);
+        require(log.get(0) == [1, 2, 3]);
+        require(log.get(1) == [4]);
+        require(log.get(2) == [5, 6, 7, 8, 9]);
+        require(AppendOnlyMerkle::is_consistent(old_root, log.merkle.root));
+        require(!AppendOnlyMerkle::is_consistent(log.merkle.root, old_root));
+    }
+
+    // a log which rewrote a past entry is not an extension of the old log
+    let mut forged = AppendOnlyMerkle::load(old_root);
+    forged.merkle.set(1, &[10], true, None);
+    forged.append(&[5, 6, 7, 8, 9]);
+    unsafe {
+        require(!AppendOnlyMerkle::is_consistent(
+            old_root,
+            forged.merkle.root,
+        ))
+    };
+}
+
+pub fn test_mmr() {
+    let mut mmr = MerkleMountainRange::new();
+    let leaves = [[1u64], [2], [3]];
+    for (i, leaf) in leaves.iter().enumerate() {
+        let index = mmr.append(leaf);
+        unsafe { require(index == i as u64) };
+    }
+    let h: Vec<[u64; 4]> = leaves
+        .iter()
+        .map(|leaf| MerkleMountainRange::leaf_hash(leaf))
+        .collect();
+    let peaks = mmr.peak_list();
+    unsafe {
+        require(mmr.count() == 3);
+        require(peaks.len() == 2);
+        require(peaks[0] == MerkleMountainRange::node_hash(&h[0], &h[1]));
+        require(peaks[1] == h[2]);
+        // a leaf holding the two children is not the node above them
+        require(MerkleMountainRange::leaf_hash(&[h[0], h[1]].concat()) != peaks[0]);
+    }
+
+    let proof = MmrProof {
+        index: 1,
+        siblings: vec![h[0]],
+    };
+    let root = mmr.root();
+    unsafe {
+        require(mmr.verify(&[2], &proof));
+        require(!mmr.verify(&[3], &proof));
+        require(MerkleMountainRange::verify_root(
+            &root,
+            3,
+            &peaks,
+            &[2],
+            &proof,
+        ));
+    }
+
+    // the fourth leaf merges everything into a single peak
+    mmr.append(&[4]);
+    let proof = MmrProof {
+        index: 3,
+        siblings: vec![h[2], MerkleMountainRange::node_hash(&h[0], &h[1])],
+    };
+    unsafe {
+        require(mmr.peak_list().len() == 1);
+        require(mmr.verify(&[4], &proof));
+        require(mmr.root() != root);
+    }
+}
+
+#[derive(LeafCodec, PartialEq, Clone, Debug)]
+struct Account {
+    balance: u64,
+    nonce: u32,
+    pk: BabyJubjubPoint,
+    history: Vec<u64>,
+}
+
+#[derive(LeafCodec, PartialEq, Clone, Debug)]
+enum Record {
+    Balance(u64),
+    Owner(U256),
+}
+
+pub fn test_typed_merkle() {
+    let mut accounts = TypedMerkle::<Account>::new();
+    let account = Account {
+        balance: 100,
+        nonce: 1,
+        pk: BabyJubjubPoint {
+            x: U256([1, 2, 3, 4]),
+            y: U256([5, 6, 7, 8]),
+        },
+        history: vec![],
+    };
+    accounts.set(3, &account);
+    unsafe {
+        require(accounts.get(0).is_none());
+        require(accounts.get(3) == Some(account.clone()));
+        require(account.to_limbs().len() == 11);
+    }
+
+    let mut records = TypedMerkle::<Record>::new();
+    records.set(0, &Record::Balance(7));
+    records.set(1, &Record::Owner(U256([9, 0, 0, 1])));
+    unsafe {
+        require(records.get(0) == Some(Record::Balance(7)));
+        require(records.get(1) == Some(Record::Owner(U256([9, 0, 0, 1]))));
+    }
+
+    // malformed preimages: an unknown variant and a truncated record
+    records.merkle.set(2, &[5, 1], true, None);
+    records.merkle.set(3, &[1, 9], true, None);
+    unsafe {
+        require(records.try_get(0) == Ok(Some(Record::Balance(7))));
+        require(records.try_get(2) == Err(MerkleError::InvalidEncoding));
+        require(records.try_get(3) == Err(MerkleError::InvalidEncoding));
+        require(Account::try_from_limbs(&account.to_limbs()[0..10]).is_none());
+        require(Record::try_from_limbs(&[0, 7, 0]).is_none());
+    }
+}
+
+pub fn test_forest() {
+    const BALANCES: u32 = 0;
+    const ORDERS: u32 = 1;
+    let mut forest = MerkleForest::new();
+    let empty_root = forest.root();
+    forest.tree(BALANCES).set(0, &[100], true, None);
+    forest.tree(BALANCES).set(1, &[200], true, None);
+    forest.tree(ORDERS).set_simple(5, &[1, 2, 3, 4], None);
+    let balances_root = forest.tree(BALANCES).root;
+    let root = forest.root();
+    unsafe { require(root != empty_root) };
+
+    let mut forest = MerkleForest::load(root);
+    let (_, balance) = forest.tree(BALANCES).get(1, true);
+    let mut order = [0; 4];
+    forest.tree(ORDERS).get_simple(5, &mut order);
+    unsafe {
+        require(forest.tree(BALANCES).root == balances_root);
+        require(balance == [200]);
+        require(order == [1, 2, 3, 4]);
+        // untouched trees do not change the root
+        require(forest.tree(2).root == Merkle::new().root);
+        require(forest.root() == root);
+    }
+}
+
+pub fn test_root_history() {
+    let mut history = RootHistory::new(Merkle::new(), 16, 2);
+    let mut mmr = MerkleMountainRange::new();
+    mmr.append(&[1]);
+    let root1 = mmr.root();
+    history.push_root(&root1);
+    mmr.append(&[2]);
+    let root2 = mmr.root();
+    history.push_root(&root2);
+    unsafe {
+        require(history.len() == 2);
+        require(history.is_known_root(&root1));
+        require(history.is_known_root_at(&root2, 1));
+        require(!history.is_known_root_at(&root2, 0));
+    }
+
+    // a proof against the older root is still accepted
+    let proof = MmrProof {
+        index: 0,
+        siblings: vec![],
+    };
+    let peaks = [MerkleMountainRange::leaf_hash(&[1])];
+    unsafe {
+        require(MerkleMountainRange::verify_recent_root(
+            &history,
+            &root1,
+            1,
+            &peaks,
+            &[1],
+            &proof,
+        ));
+    }
+
+    // the oldest root is dropped once the buffer is full
+    mmr.append(&[3]);
+    history.push_root(&mmr.root());
+    unsafe {
+        require(history.len() == 2);
+        require(!history.is_known_root(&root1));
+        require(history.find_root(&root2) == Some(1));
+        require(!MerkleMountainRange::verify_recent_root(
+            &history,
+            &root1,
+            1,
+            &peaks,
+            &[1],
+            &proof,
+        ));
+    }
 }
 
 pub fn test_jubjub() {
@@ -389,12 +1398,60 @@ pub fn zkmain() -> i64 {
     if true {
         crate::dbg!("testing merkle\n");
         test_merkle();
+        crate::dbg!("testing merkle errors\n");
+        test_merkle_errors();
         crate::dbg!("testing jubjub\n");
         test_jubjub();
         crate::dbg!("testing kvpair\n");
         test_kvpair();
+        crate::dbg!("testing kvpair remove\n");
+        test_kvpair_remove();
+        crate::dbg!("testing kvpair legacy node\n");
+        test_kvpair_legacy_node();
+        #[cfg(feature = "native")]
+        {
+            crate::dbg!("testing kvpair non membership\n");
+            test_kvpair_non_membership();
+        }
+        crate::dbg!("testing typed kvpair\n");
+        test_typed_kvpair();
+        crate::dbg!("testing kvpair entry\n");
+        test_kvpair_entry();
+        crate::dbg!("testing bytes kvpair\n");
+        test_bytes_kvpair();
+        crate::dbg!("testing namespace\n");
+        test_namespace();
+        crate::dbg!("testing compact kvpair\n");
+        test_compact_kvpair();
+        crate::dbg!("testing kvpair batch\n");
+        test_kvpair_batch();
+        #[cfg(feature = "native")]
+        {
+            crate::dbg!("testing native backend\n");
+            test_native();
+        }
         crate::dbg!("testing kvpair u64\n");
         test_kvpair_u64();
+        crate::dbg!("testing checkpoint\n");
+        test_checkpoint();
+        crate::dbg!("testing append only merkle\n");
+        test_append_only();
+        crate::dbg!("testing merkle mountain range\n");
+        test_mmr();
+        crate::dbg!("testing typed merkle\n");
+        test_typed_merkle();
+        crate::dbg!("testing merkle forest\n");
+        test_forest();
+        crate::dbg!("testing merkle vec\n");
+        test_merkle_vec();
+        crate::dbg!("testing merkle queue\n");
+        test_merkle_queue();
+        crate::dbg!("testing bitmap\n");
+        test_bitmap();
+        crate::dbg!("testing ordered map\n");
+        test_ordered_map();
+        crate::dbg!("testing root history\n");
+        test_root_history();
     }
     if true {
         witness_test::test_witness_obj();